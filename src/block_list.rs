@@ -1,52 +1,203 @@
-use crate::opts::ServeArgs;
+use crate::client_ip::client_ip;
+use crate::opts::{parse_ip_net, ServeArgs};
 use crate::serve::DumpError;
-use log::info;
+use ipnet::IpNet;
+use log::{info, warn};
 use poem::Result;
 use poem::{error::Forbidden, Request};
-use std::collections::HashSet;
 use std::net::IpAddr;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 use poem::{async_trait, Endpoint, Middleware};
-pub struct DenyIps {
-    ips: HashSet<IpAddr>,
+
+// How often the list files are checked for changes, SIGHUP reloads them immediately
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// Each line contains an address or a CIDR range, `#` starts a comment
+fn parse_ip_list(path: &Path, text: &str) -> Vec<IpNet> {
+    let mut nets = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match parse_ip_net(entry) {
+            Ok(net) => nets.push(net),
+            Err(_) => warn!(
+                "Skipping invalid entry {:?} in {}:{}",
+                entry,
+                path.display(),
+                index + 1
+            ),
+        }
+    }
+    nets
+}
+
+struct IpList {
+    path: PathBuf,
+    nets: RwLock<Vec<IpNet>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl IpList {
+    fn load(path: PathBuf) -> Result<IpList, String> {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Ok(IpList {
+            nets: RwLock::new(parse_ip_list(&path, &text)),
+            modified: RwLock::new(std::fs::metadata(&path).and_then(|m| m.modified()).ok()),
+            path,
+        })
+    }
+
+    // Keeps the previous entries if the file can not be read
+    fn reload(&self, force: bool) {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if !force && modified == *self.modified.read().unwrap() {
+            return;
+        }
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => {
+                let nets = parse_ip_list(&self.path, &text);
+                info!("Loaded {} entries from {}", nets.len(), self.path.display());
+                *self.nets.write().unwrap() = nets;
+                *self.modified.write().unwrap() = modified;
+            }
+            Err(e) => warn!("Could not reload {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.read().unwrap().iter().any(|net| net.contains(ip))
+    }
+}
+
+enum Mode {
+    Deny,
+    Allow,
+}
+
+struct IpRule {
+    list: IpList,
+    mode: Mode,
+}
+
+impl IpRule {
+    // Clients without an address can not be on an allowlist
+    fn permits(&self, ip: Option<&IpAddr>) -> bool {
+        match (&self.mode, ip) {
+            (Mode::Deny, Some(ip)) => !self.list.contains(ip),
+            (Mode::Deny, None) => true,
+            (Mode::Allow, Some(ip)) => self.list.contains(ip),
+            (Mode::Allow, None) => false,
+        }
+    }
 }
 
-impl<E: Endpoint> Middleware<E> for DenyIps {
-    type Output = DenyIpsImpl<E>;
+// Rejects clients which are blocked, or not allowed, by any of its rules
+#[derive(Clone)]
+pub struct IpFilter {
+    rules: Arc<Vec<IpRule>>,
+}
+
+impl IpFilter {
+    fn new(blocked: &Option<PathBuf>, allowed: &Option<PathBuf>) -> Result<IpFilter, String> {
+        let mut rules = Vec::new();
+        if let Some(path) = blocked {
+            rules.push(IpRule {
+                list: IpList::load(path.clone())?,
+                mode: Mode::Deny,
+            });
+        }
+        if let Some(path) = allowed {
+            rules.push(IpRule {
+                list: IpList::load(path.clone())?,
+                mode: Mode::Allow,
+            });
+        }
+        Ok(IpFilter {
+            rules: Arc::new(rules),
+        })
+    }
+
+    fn reload(&self, force: bool) {
+        for rule in self.rules.iter() {
+            rule.list.reload(force);
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for IpFilter {
+    type Output = IpFilterImpl<E>;
 
     fn transform(&self, ep: E) -> Self::Output {
-        DenyIpsImpl(self.ips.clone(), ep)
+        IpFilterImpl(self.clone(), ep)
     }
 }
 
-pub struct DenyIpsImpl<E>(HashSet<IpAddr>, E);
+pub struct IpFilterImpl<E>(IpFilter, E);
 
 #[async_trait]
-impl<E: Endpoint> Endpoint for DenyIpsImpl<E> {
+impl<E: Endpoint> Endpoint for IpFilterImpl<E> {
     type Output = <E as Endpoint>::Output;
 
     async fn call(&self, req: Request) -> Result<Self::Output> {
-        let remote_ip = req.remote_addr().0.as_socket_addr().unwrap().ip();
-        if self.0.contains(&remote_ip) {
-            info!("Blocked IP: {}", remote_ip);
-            return Err(Forbidden(DumpError::new("Forbidden".to_string())));
+        let ip = client_ip(&req);
+        if !self.0.rules.iter().all(|rule| rule.permits(ip.as_ref())) {
+            info!("Blocked IP: {:?}", ip);
+            return Err(Forbidden(DumpError::new(
+                "ip_blocked",
+                "Forbidden".to_string(),
+            )));
         }
         self.1.call(req).await
     }
 }
 
-pub fn build_deny_ips(args: &ServeArgs) -> DenyIps {
-    let mut ips = HashSet::new();
-    if args.blocked_ips.is_none() {
-        return DenyIps { ips };
-    }
-    let blocked_ips_file =
-        std::fs::read_to_string(&args.blocked_ips.clone().unwrap()).expect("Could not read file");
-    for mut ip_str in blocked_ips_file.lines() {
-        ip_str = ip_str.trim();
-        let ip = IpAddr::from_str(&ip_str).expect("Could not parse IP");
-        ips.insert(ip);
+// Reading the files blocks, so it runs on the blocking thread pool
+async fn reload_filters(filters: &[IpFilter], force: bool) {
+    let filters = filters.to_vec();
+    let result = tokio::task::spawn_blocking(move || {
+        for filter in &filters {
+            filter.reload(force);
+        }
+    })
+    .await;
+    if let Err(e) = result {
+        warn!("Could not reload IP lists: {}", e);
     }
-    DenyIps { ips }
+}
+
+// Reloads the lists when their files change, or immediately on SIGHUP
+fn watch_ip_filters(filters: Vec<IpFilter>) {
+    let polled_filters = filters.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            reload_filters(&polled_filters, false).await;
+        }
+    });
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading IP lists");
+            reload_filters(&filters, true).await;
+        }
+    });
+}
+
+// Returns the filter for all requests and the filter for uploads, fails if a list can not be read
+pub fn build_ip_filters(args: &ServeArgs) -> Result<(IpFilter, IpFilter), String> {
+    let access_filter = IpFilter::new(&args.blocked_ips, &args.allowed_ips)?;
+    let upload_filter = IpFilter::new(&args.blocked_upload_ips, &args.allowed_upload_ips)?;
+    watch_ip_filters(vec![access_filter.clone(), upload_filter.clone()]);
+    Ok((access_filter, upload_filter))
 }
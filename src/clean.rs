@@ -1,24 +1,72 @@
-use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::{
-    models::{File, Url},
+    db::{build_pool, migrate_database},
+    models::{File, Upload, Url},
+    opts::CleanArgs,
+    quota::quota_window_start,
+    reconcile::reconcile,
+    storage::{build_storage, check_storage, removes_orphans},
     util::create_connection,
 };
 
-pub async fn clean(data_directory: PathBuf) {
-    let files_directory = data_directory.join("files");
-    std::fs::create_dir_all(&files_directory).expect("Could not create files directory");
+pub async fn clean(args: CleanArgs) {
+    let data_directory = args.data_directory;
+    if let Err(e) = check_storage(&args.storage, &data_directory) {
+        eprintln!("{}, pass the same storage options as to dump serve", e);
+        std::process::exit(1);
+    }
+    let storage = build_storage(&args.storage, &data_directory);
+    migrate_database(&data_directory);
+    let pool = build_pool(&data_directory, 1, Duration::from_secs(5));
+    let prune_orphans = removes_orphans(&args.storage, args.prune_orphans);
+    let report = match reconcile(
+        &data_directory,
+        storage.as_ref(),
+        &pool,
+        args.prune_missing,
+        prune_orphans,
+    )
+    .await
+    {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Could not reconcile storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Removed {} stale uploads", report.stale_uploads);
+    if report.pruned_missing {
+        println!("Removed {} files with missing blobs", report.missing_blobs);
+    } else {
+        println!(
+            "Found {} files with missing blobs, pass --prune-missing to remove them",
+            report.missing_blobs
+        );
+    }
+    if report.pruned_orphans {
+        println!("Removed {} orphaned blobs", report.orphaned_blobs);
+    } else {
+        println!(
+            "Found {} blobs unknown to the database, pass --prune-orphans to remove them",
+            report.orphaned_blobs
+        );
+    }
     let connection = create_connection(&data_directory).expect("Could not create connection");
     println!(
         "Found {} expired urls",
         Url::count_expired(&connection).expect("Could not count expired urls")
     );
     Url::delete_expired(&connection).expect("Could not delete expired urls");
+    Upload::prune_all(&connection, quota_window_start()).expect("Could not prune uploads");
     let unlinked_files =
         File::search_unlinked(&connection).expect("Could not search unlinked files");
     println!("Found {} unlinked files", unlinked_files.len());
     for file in unlinked_files {
-        std::fs::remove_file(files_directory.join(&file.hash)).expect("Could not delete file");
+        storage
+            .delete(&file.hash)
+            .await
+            .expect("Could not delete file");
     }
     File::delete_unlinked(&connection).expect("Could not delete unlinked files");
 }
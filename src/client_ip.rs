@@ -0,0 +1,256 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use poem::{async_trait, Endpoint, Middleware, Request, Result};
+
+use crate::opts::ProxyHeader;
+
+// The address of the client, resolved from proxy headers if the request came from a trusted proxy.
+// None for requests via Unix sockets without proxy headers
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    match req.extensions().get::<ClientIp>() {
+        Some(ClientIp(ip)) => *ip,
+        None => peer_ip(req),
+    }
+}
+
+fn peer_ip(req: &Request) -> Option<IpAddr> {
+    req.remote_addr()
+        .0
+        .as_socket_addr()
+        .map(|address| address.ip())
+}
+
+// Parses a node of a proxy header, which may be quoted and contain a port,
+// e.g. `192.0.2.43`, `"[2001:db8::1]:4711"` or `192.0.2.43:47011`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next().and_then(|ip| ip.parse().ok());
+    }
+    IpAddr::from_str(node)
+        .ok()
+        .or_else(|| SocketAddr::from_str(node).ok().map(|address| address.ip()))
+}
+
+// The `for` parameters of a `Forwarded` header, see RFC 7239
+fn forwarded_nodes(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .collect()
+}
+
+struct ProxyConfig {
+    trusted_proxies: Vec<IpNet>,
+    header: ProxyHeader,
+}
+
+pub struct ResolveClientIp {
+    config: Arc<ProxyConfig>,
+}
+
+impl ResolveClientIp {
+    pub fn new(trusted_proxies: Vec<IpNet>, header: ProxyHeader) -> ResolveClientIp {
+        ResolveClientIp {
+            config: Arc::new(ProxyConfig {
+                trusted_proxies,
+                header,
+            }),
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for ResolveClientIp {
+    type Output = ResolveClientIpImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ResolveClientIpImpl(self.config.clone(), ep)
+    }
+}
+
+pub struct ResolveClientIpImpl<E>(Arc<ProxyConfig>, E);
+
+impl<E> ResolveClientIpImpl<E> {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.0.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    // All lines of the header in order, a proxy may append its own line after the ones of the client
+    fn chain<'a>(&self, req: &'a Request) -> Vec<&'a str> {
+        let values = req
+            .headers()
+            .get_all(self.0.header.header_name())
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        match self.0.header {
+            ProxyHeader::Forwarded => values.flat_map(forwarded_nodes).collect(),
+            ProxyHeader::XForwardedFor | ProxyHeader::XRealIp => {
+                values.flat_map(|value| value.split(',')).collect()
+            }
+        }
+    }
+
+    // Proxies append the address they received the request from, so the chain is walked from the
+    // right and the first address which is not a trusted proxy is the client
+    fn resolve(&self, peer: Option<IpAddr>, chain: &[&str]) -> Option<IpAddr> {
+        // Unix sockets are only reachable by local proxies
+        let peer_trusted = match &peer {
+            Some(peer) => self.is_trusted(peer),
+            None => !self.0.trusted_proxies.is_empty(),
+        };
+        if !peer_trusted {
+            return peer;
+        }
+        let mut client = peer;
+        for node in chain.iter().rev() {
+            match parse_node(node) {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                // Obfuscated or unknown nodes can not be followed any further
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for ResolveClientIpImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let ip = self.resolve(peer_ip(&req), &self.chain(&req));
+        req.extensions_mut().insert(ClientIp(ip));
+        self.1.call(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusting(trusted_proxies: &[&str], header: ProxyHeader) -> ResolveClientIpImpl<()> {
+        ResolveClientIpImpl(
+            Arc::new(ProxyConfig {
+                trusted_proxies: trusted_proxies
+                    .iter()
+                    .map(|net| net.parse().unwrap())
+                    .collect(),
+                header,
+            }),
+            (),
+        )
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let resolver = trusting(&["10.0.0.0/8"], ProxyHeader::XForwardedFor);
+        assert_eq!(
+            resolver.resolve(ip("203.0.113.5"), &["198.51.100.7"]),
+            ip("203.0.113.5")
+        );
+        // Without trusted proxies, not even local peers may set the client address
+        let resolver = trusting(&[], ProxyHeader::XForwardedFor);
+        assert_eq!(
+            resolver.resolve(ip("127.0.0.1"), &["198.51.100.7"]),
+            ip("127.0.0.1")
+        );
+        assert_eq!(resolver.resolve(None, &["198.51.100.7"]), None);
+    }
+
+    #[test]
+    fn follows_trusted_hops() {
+        let resolver = trusting(&["10.0.0.0/8", "192.0.2.1/32"], ProxyHeader::XForwardedFor);
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &["198.51.100.7"]),
+            ip("198.51.100.7")
+        );
+        // The client may send its own header, only the nodes added by trusted proxies count
+        assert_eq!(
+            resolver.resolve(
+                ip("10.0.0.1"),
+                &["203.0.113.9", "198.51.100.7", "192.0.2.1", "10.0.0.2"]
+            ),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &["10.0.0.3", "10.0.0.2"]),
+            ip("10.0.0.3")
+        );
+        assert_eq!(resolver.resolve(ip("10.0.0.1"), &[]), ip("10.0.0.1"));
+        // Unix sockets are trusted once proxies are configured
+        assert_eq!(
+            resolver.resolve(None, &["198.51.100.7"]),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn stops_at_unparsable_nodes() {
+        let resolver = trusting(&["10.0.0.0/8"], ProxyHeader::Forwarded);
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &["198.51.100.7", "unknown", "10.0.0.2"]),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &["198.51.100.7", "_hidden"]),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.43"), ip("192.0.2.43"));
+        assert_eq!(parse_node(" 192.0.2.43:47011"), ip("192.0.2.43"));
+        assert_eq!(parse_node("\"192.0.2.43\""), ip("192.0.2.43"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("\"[2001:db8::1]:4711\""), ip("2001:db8::1"));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("\"[not an address]\""), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn reads_forwarded_nodes() {
+        assert_eq!(
+            forwarded_nodes(
+                "for=192.0.2.60;proto=http;by=203.0.113.43, For=\"[2001:db8:cafe::17]:4711\""
+            ),
+            vec!["192.0.2.60", "\"[2001:db8:cafe::17]:4711\""]
+        );
+        assert_eq!(forwarded_nodes("proto=https, for=unknown"), vec!["unknown"]);
+    }
+
+    #[test]
+    fn reads_the_configured_header() {
+        let req = Request::builder()
+            .header("Forwarded", "for=198.51.100.7")
+            .header("X-Forwarded-For", "203.0.113.9, 192.0.2.1")
+            .finish();
+        let resolver = trusting(&["10.0.0.0/8"], ProxyHeader::XForwardedFor);
+        assert_eq!(resolver.chain(&req), vec!["203.0.113.9", " 192.0.2.1"]);
+        let resolver = trusting(&["10.0.0.0/8"], ProxyHeader::Forwarded);
+        assert_eq!(resolver.chain(&req), vec!["198.51.100.7"]);
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+use std::path::Path;
+
+use async_compression::tokio::bufread::{BrotliDecoder, BrotliEncoder, ZstdDecoder, ZstdEncoder};
+use poem::error::InternalServerError;
+use poem::Result;
+use tokio::io::BufReader;
+
+use crate::opts::Compression;
+use crate::storage::BlobReader;
+use crate::upload::TempUpload;
+
+// Compresses an upload into a new file in the tmp directory.
+// Returns None if compression is disabled or does not make the file smaller
+pub async fn compress(
+    upload: &TempUpload,
+    compression: &Compression,
+    data_directory: &Path,
+) -> Result<Option<TempUpload>> {
+    let file = tokio::fs::File::open(&upload.path)
+        .await
+        .map_err(InternalServerError)?;
+    let reader = BufReader::new(file);
+    let compressed = match compression {
+        Compression::None => return Ok(None),
+        Compression::Zstd => {
+            TempUpload::receive(ZstdEncoder::new(reader), data_directory, usize::MAX).await?
+        }
+        Compression::Brotli => {
+            TempUpload::receive(BrotliEncoder::new(reader), data_directory, usize::MAX).await?
+        }
+    };
+    if compressed.size >= upload.size {
+        return Ok(None);
+    }
+    Ok(Some(compressed))
+}
+
+// Decompresses a stored stream, the encoding is the content coding stored with the file
+pub fn decompress(reader: BlobReader, encoding: &str) -> io::Result<BlobReader> {
+    let reader = BufReader::new(reader);
+    match encoding {
+        "zstd" => Ok(Box::new(ZstdDecoder::new(reader))),
+        "br" => Ok(Box::new(BrotliDecoder::new(reader))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown encoding {}", encoding),
+        )),
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use aes::Aes256;
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use hkdf::Hkdf;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::random;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+
+use crate::opts::Encryption;
+
+// Blobs are encrypted with AES-256-CTR, which allows decrypting arbitrary ranges
+type BlobCipher = ctr::Ctr128BE<Aes256>;
+
+pub type Key = [u8; 32];
+
+const NONCE_SIZE: usize = 12;
+const CHUNK_SIZE: usize = 64 * 1024;
+// About 130 bits of entropy
+const LINK_KEY_LENGTH: usize = 22;
+
+// Convergent encryption: the key of a blob is the SHA-256 of its content, so identical uploads
+// still deduplicate, while the key itself is only stored wrapped inside of the url.
+// Anyone who knows the content can derive its key and blob id, and thereby confirm it was uploaded
+pub fn content_key(hash: &str) -> Key {
+    let mut key = [0; 32];
+    hex::decode_to_slice(hash, &mut key).expect("Content hashes are hex encoded SHA-256 digests");
+    key
+}
+
+// Encrypted blobs are stored under the hash of their key, which does not reveal the key
+pub fn blob_id(content_key: &Key) -> String {
+    format!("{:x}", Sha256::digest(content_key))
+}
+
+fn blob_cipher(content_key: &Key, offset: u64) -> BlobCipher {
+    let mut cipher =
+        BlobCipher::new_from_slices(content_key, &[0; 16]).expect("Keys have a valid length");
+    cipher.seek(offset);
+    cipher
+}
+
+pub async fn encrypt_in_place(path: &Path, content_key: &Key) -> Result<(), std::io::Error> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .await?;
+    let mut cipher = blob_cipher(content_key, 0);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut position = 0;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        cipher.apply_keystream(&mut buffer[..read]);
+        file.seek(SeekFrom::Start(position)).await?;
+        file.write_all(&buffer[..read]).await?;
+        position += read as u64;
+    }
+    file.flush().await
+}
+
+// Decrypts a blob which has been opened at the given offset
+pub struct DecryptingReader<R> {
+    inner: R,
+    cipher: BlobCipher,
+}
+
+impl<R> DecryptingReader<R> {
+    pub fn new(inner: R, content_key: &Key, offset: u64) -> Self {
+        DecryptingReader {
+            inner,
+            cipher: blob_cipher(content_key, offset),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.cipher.apply_keystream(&mut buf.filled_mut()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The part of a link which token mode derives the wrapping key from. It is never stored
+pub fn generate_link_key() -> String {
+    Alphanumeric.sample_string(&mut OsRng, LINK_KEY_LENGTH)
+}
+
+// Derives the key used to wrap content keys. In token mode the server can only decrypt
+// a blob while handling a request for its url, as the link key is never stored
+pub fn wrapping_key(
+    encryption: &Encryption,
+    link_key: &str,
+    master_key: Option<&str>,
+) -> Result<Key, &'static str> {
+    match encryption {
+        Encryption::None => Err("Url is not encrypted"),
+        Encryption::Token => {
+            let mut key = [0; 32];
+            Hkdf::<Sha256>::new(None, link_key.as_bytes())
+                .expand(b"dump token wrapping key", &mut key)
+                .expect("Keys have a valid length");
+            Ok(key)
+        }
+        Encryption::Master => {
+            let mut key = [0; 32];
+            hex::decode_to_slice(master_key.ok_or("Missing master key")?, &mut key)
+                .map_err(|_e| "Master key must be 32 hex encoded bytes")?;
+            Ok(key)
+        }
+    }
+}
+
+// Returns the nonce followed by the AES-256-GCM encrypted content key
+pub fn wrap_key(content_key: &Key, wrapping_key: &Key) -> Vec<u8> {
+    let cipher = Aes256Gcm::new_from_slice(wrapping_key).expect("Keys have a valid length");
+    let nonce = random::<[u8; NONCE_SIZE]>();
+    let mut wrapped_key = nonce.to_vec();
+    wrapped_key.extend(
+        cipher
+            .encrypt(Nonce::from_slice(&nonce), content_key.as_slice())
+            .expect("Could not encrypt key"),
+    );
+    wrapped_key
+}
+
+pub fn unwrap_key(wrapped_key: &[u8], wrapping_key: &Key) -> Option<Key> {
+    if wrapped_key.len() < NONCE_SIZE {
+        return None;
+    }
+    let cipher = Aes256Gcm::new_from_slice(wrapping_key).expect("Keys have a valid length");
+    let (nonce, ciphertext) = wrapped_key.split_at(NONCE_SIZE);
+    let content_key = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+    content_key.try_into().ok()
+}
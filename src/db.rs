@@ -0,0 +1,490 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use poem::error::InternalServerError;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::util::{create_connection, hash_password, hash_token};
+
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+// WAL journaling lets readers continue while an upload writes, the busy timeout
+// makes concurrent writers wait for each other instead of failing with `database is locked`
+pub fn build_pool(data_directory: &Path, size: u32, busy_timeout: Duration) -> DbPool {
+    let manager =
+        SqliteConnectionManager::file(data_directory.join("db.sqlite3")).with_init(move |conn| {
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |_row| Ok(()))?;
+            conn.busy_timeout(busy_timeout)
+        });
+    r2d2::Pool::builder()
+        .max_size(size)
+        .build(manager)
+        .expect("Could not create database pool")
+}
+
+// SQLite calls block, so they run on the blocking thread pool of tokio
+pub async fn with_connection<T, F>(pool: &DbPool, f: F) -> poem::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let connection = pool.get().map_err(InternalServerError)?;
+        f(&connection).map_err(InternalServerError)
+    })
+    .await
+    .map_err(InternalServerError)?
+}
+
+// Migrations are applied in order, the schema version stored in `PRAGMA user_version`
+// is the number of applied migrations. Released migrations must never be changed,
+// new columns need a new migration at the end of the list
+struct Migration {
+    name: &'static str,
+    apply: fn(&Connection) -> Result<(), rusqlite::Error>,
+    // Long running migrations commit their progress themselves, so an interrupted run continues
+    // where it stopped. They must leave a consistent schema after every commit and be safe to repeat
+    transaction: bool,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "create files and urls tables",
+        apply: create_tables,
+        transaction: true,
+    },
+    Migration {
+        name: "add download limits and passwords",
+        apply: add_download_limits,
+        transaction: true,
+    },
+    Migration {
+        name: "add encryption",
+        apply: add_encryption,
+        transaction: true,
+    },
+    Migration {
+        name: "add compression",
+        apply: add_compression,
+        transaction: true,
+    },
+    Migration {
+        name: "hash delete secrets",
+        apply: hash_secrets,
+        transaction: false,
+    },
+    Migration {
+        name: "add download counts and content dispositions",
+        apply: add_url_management,
+        transaction: true,
+    },
+    Migration {
+        name: "add uploaders",
+        apply: add_uploaders,
+        transaction: true,
+    },
+    Migration {
+        name: "add api keys",
+        apply: create_api_keys,
+        transaction: true,
+    },
+    Migration {
+        name: "hash tokens",
+        apply: hash_tokens,
+        transaction: true,
+    },
+    Migration {
+        name: "add upload log",
+        apply: create_uploads,
+        transaction: true,
+    },
+];
+
+fn create_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS files (
+          hash TEXT PRIMARY KEY,
+          size INTEGER NOT NULL,
+          mime TEXT,
+          file_type TEXT
+        );
+        CREATE TABLE IF NOT EXISTS urls (
+          token TEXT PRIMARY KEY,
+          file_hash TEXT,
+          secret TEXT,
+          expires TEXT NOT NULL,
+          file_name TEXT NOT NULL,
+          FOREIGN KEY(file_hash) REFERENCES files(hash)
+        );",
+    )
+}
+
+fn add_download_limits(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column(conn, "urls", "remaining_downloads", "INTEGER")?;
+    add_column(conn, "urls", "password_hash", "TEXT")
+}
+
+fn add_encryption(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column(conn, "files", "encrypted", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "urls", "encryption", "TEXT")?;
+    add_column(conn, "urls", "wrapped_key", "BLOB")
+}
+
+fn add_compression(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column(conn, "files", "stored_size", "INTEGER")?;
+    add_column(conn, "files", "encoding", "TEXT")
+}
+
+// Number of secrets hashed per transaction
+const HASH_SECRETS_BATCH: usize = 100;
+
+// Secrets were stored in plain text, hashing every secret may take a while on large databases.
+// Each batch is committed on its own, so the database is not locked for the whole migration and
+// an interrupted migration skips the secrets which are already hashed.
+// Urls without a secret can not be deleted and are left as they are
+fn hash_secrets(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let secrets = conn
+        .prepare(
+            "SELECT token, secret FROM urls WHERE secret IS NOT NULL AND secret NOT LIKE '$argon2%'",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+    let total = secrets.len();
+    for (batch, chunk) in secrets.chunks(HASH_SECRETS_BATCH).enumerate() {
+        log::info!(
+            "Hashing delete secrets {} to {} of {}",
+            batch * HASH_SECRETS_BATCH + 1,
+            batch * HASH_SECRETS_BATCH + chunk.len(),
+            total
+        );
+        // Hashing is slow, the write lock is only taken to store the hashes
+        let hashes = chunk
+            .iter()
+            .map(|(token, secret)| (hash_password(secret), token))
+            .collect::<Vec<(String, &String)>>();
+        let transaction = conn.unchecked_transaction()?;
+        {
+            let mut statement =
+                transaction.prepare("UPDATE urls SET secret = ?1 WHERE token = ?2")?;
+            for (secret_hash, token) in hashes {
+                statement.execute((secret_hash, token))?;
+            }
+        }
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn add_url_management(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column(conn, "urls", "downloads", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "urls", "content_disposition", "TEXT")
+}
+
+// Urls created before this migration have no uploader and do not count towards the daily limits
+fn add_uploaders(conn: &Connection) -> Result<(), rusqlite::Error> {
+    add_column(conn, "urls", "uploader", "TEXT")?;
+    add_column(conn, "urls", "created", "TEXT")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS urls_uploader_created ON urls(uploader, created)",
+        (),
+    )
+    .map(|_| ())
+}
+
+fn create_api_keys(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+          id INTEGER PRIMARY KEY,
+          name TEXT NOT NULL,
+          key_hash TEXT NOT NULL UNIQUE,
+          created TEXT NOT NULL,
+          revoked TEXT,
+          max_size INTEGER,
+          max_expires INTEGER,
+          allowed_groups TEXT,
+          rate_limit_factor REAL,
+          daily_upload_bytes INTEGER,
+          daily_upload_files INTEGER
+        );",
+    )
+}
+
+fn hash_tokens(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let tokens = conn
+        .prepare("SELECT token FROM urls")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+    for token in tokens {
+        conn.execute(
+            "UPDATE urls SET token = ?1 WHERE token = ?2",
+            (hash_token(&token), token),
+        )?;
+    }
+    Ok(())
+}
+
+// The daily usage was counted from the urls, which are copied to keep it after the update
+fn create_uploads(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS uploads (
+          uploader TEXT NOT NULL,
+          created TEXT NOT NULL,
+          size INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS uploads_uploader_created ON uploads(uploader, created);
+        CREATE INDEX IF NOT EXISTS uploads_created ON uploads(created);
+        INSERT INTO uploads (uploader, created, size)
+          SELECT urls.uploader, urls.created, files.size
+          FROM urls JOIN files ON files.hash = urls.file_hash
+          WHERE urls.uploader IS NOT NULL AND urls.created IS NOT NULL;",
+    )
+}
+
+// Databases created before versioning may already contain some of the columns
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), rusqlite::Error> {
+    let exists = conn
+        .query_row(
+            &format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ),
+            (column,),
+            |_row| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn schema_version(conn: &Connection) -> Result<usize, rusqlite::Error> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(rusqlite::Error),
+    // The database was migrated by a newer version of dump
+    UnsupportedVersion(usize),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "Could not migrate database: {}", e),
+            MigrationError::UnsupportedVersion(version) => write!(
+                f,
+                "The database has schema version {}, but this version of dump only supports up to {}",
+                version,
+                MIGRATIONS.len()
+            ),
+        }
+    }
+}
+
+impl Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+// Applies all pending migrations, each in its own transaction. Returns the names of the applied migrations
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static str>, MigrationError> {
+    migrate_to(conn, MIGRATIONS.len())
+}
+
+fn migrate_to(conn: &mut Connection, target: usize) -> Result<Vec<&'static str>, MigrationError> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(MigrationError::UnsupportedVersion(version));
+    }
+    let mut applied = Vec::new();
+    for (index, migration) in MIGRATIONS.iter().enumerate().take(target).skip(version) {
+        if migration.transaction {
+            let transaction = conn.transaction()?;
+            (migration.apply)(&transaction)?;
+            transaction.pragma_update(None, "user_version", index + 1)?;
+            transaction.commit()?;
+        } else {
+            (migration.apply)(conn)?;
+            conn.pragma_update(None, "user_version", index + 1)?;
+        }
+        applied.push(migration.name);
+    }
+    Ok(applied)
+}
+
+// Run by every command before it accesses the database
+pub fn migrate_database(data_directory: &PathBuf) {
+    let mut connection = create_connection(data_directory).expect("Could not create connection");
+    match migrate(&mut connection) {
+        Ok(applied) => {
+            for name in applied {
+                log::info!("Applied database migration: {}", name);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub async fn db_migrate(data_directory: PathBuf) {
+    let mut connection = create_connection(&data_directory).expect("Could not create connection");
+    let applied = match migrate(&mut connection) {
+        Ok(applied) => applied,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for name in &applied {
+        println!("Applied: {}", name);
+    }
+    println!(
+        "Applied {} migrations, schema version is {}",
+        applied.len(),
+        schema_version(&connection).expect("Could not read schema version")
+    );
+}
+
+pub async fn db_status(data_directory: PathBuf) {
+    let connection = create_connection(&data_directory).expect("Could not create connection");
+    let version = schema_version(&connection).expect("Could not read schema version");
+    println!("Schema version: {} of {}", version, MIGRATIONS.len());
+    if version > MIGRATIONS.len() {
+        println!("{}", MigrationError::UnsupportedVersion(version));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        let state = if index < version {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!("{:>3} {:<8} {}", index + 1, state, migration.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The columns of every table, to compare schemas which were migrated along different paths
+    fn columns(conn: &Connection) -> Vec<(String, String)> {
+        conn.prepare(
+            "SELECT m.name, p.name FROM sqlite_master m JOIN pragma_table_info(m.name) p
+             WHERE m.type = 'table' ORDER BY m.name, p.cid",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()
+        .unwrap()
+    }
+
+    fn insert_upload(conn: &Connection, token: &str, secret: &str) {
+        conn.execute(
+            "INSERT OR IGNORE INTO files (hash, size, mime, file_type) VALUES('abc', 3, 'text/plain', 'text')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO urls (token, file_hash, secret, expires, file_name)
+             VALUES(?1, 'abc', ?2, '2100-01-01T00:00:00+00:00', 'file.txt')",
+            (token, secret),
+        )
+        .unwrap();
+    }
+
+    fn migrated_columns() -> Vec<(String, String)> {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        columns(&conn)
+    }
+
+    // Databases of versions before migrations existed have no schema version, but may contain
+    // some of the later columns
+    #[test]
+    fn upgrades_pre_versioning_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        add_download_limits(&conn).unwrap();
+        insert_upload(&conn, "token", "secret");
+
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(columns(&conn), migrated_columns());
+        let (token, secret): (String, String) = conn
+            .query_row("SELECT token, secret FROM urls", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(token, hash_token("token"));
+        assert!(secret.starts_with("$argon2"));
+    }
+
+    #[test]
+    fn upgrades_from_every_version() {
+        let expected = migrated_columns();
+        for version in 0..=MIGRATIONS.len() {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate_to(&mut conn, version).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), version);
+            if version > 0 {
+                insert_upload(&conn, "token", "secret");
+            }
+
+            let applied = migrate(&mut conn).unwrap();
+            assert_eq!(applied.len(), MIGRATIONS.len() - version);
+            assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+            assert_eq!(columns(&conn), expected, "from version {}", version);
+            let urls: usize = conn
+                .query_row("SELECT COUNT(*) FROM urls", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(urls, usize::from(version > 0), "from version {}", version);
+        }
+    }
+
+    #[test]
+    fn keeps_missing_secrets() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 4).unwrap();
+        insert_upload(&conn, "token", "secret");
+        conn.execute("UPDATE urls SET secret = NULL", ()).unwrap();
+
+        migrate(&mut conn).unwrap();
+        let secret: Option<String> = conn
+            .query_row("SELECT secret FROM urls", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(secret, None);
+    }
+
+    #[test]
+    fn rejects_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::UnsupportedVersion(version)) if version == MIGRATIONS.len() + 1
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use poem::Request;
+
+use crate::models::{File, Url};
+use crate::opts::{ContentDisposition, ServeArgs};
+
+// Browsers execute these types when they are displayed inline, regardless of their group
+const ACTIVE_MIMES: &[&str] = &[
+    "text/html",
+    "text/xml",
+    "application/xml",
+    "application/javascript",
+    "text/javascript",
+    "application/ecmascript",
+    "text/ecmascript",
+];
+
+// Ignores parameters like `; charset=utf-8` and the case of the type. Every XML based type,
+// e.g. `image/svg+xml`, may be rendered as a document with scripts
+fn is_active_mime(mime: &str) -> bool {
+    let essence = mime
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.ends_with("+xml") || ACTIVE_MIMES.contains(&essence.as_str())
+}
+
+// `?download` forces the browser to save the file
+pub fn download_requested(req: &Request) -> bool {
+    req.uri().query().map_or(false, |query| {
+        query
+            .split('&')
+            .any(|parameter| parameter.split('=').next() == Some("download"))
+    })
+}
+
+// The disposition set for the url, otherwise the configured default. Files which could run
+// scripts in the context of this site are always downloaded
+pub fn effective_disposition(
+    url: &Url,
+    file: &File,
+    state: &ServeArgs,
+    download: bool,
+) -> ContentDisposition {
+    let forced =
+        download || state.attachment_groups.contains(&file.group) || is_active_mime(&file.mime);
+    if forced {
+        return ContentDisposition::Attachment;
+    }
+    url.content_disposition
+        .as_deref()
+        .and_then(ContentDisposition::from_name)
+        .unwrap_or_else(|| state.content_disposition.clone())
+}
+
+// Characters which may appear unencoded in `filename*`, see RFC 8187
+fn is_attr_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte)
+}
+
+// Builds the header value as described in RFC 6266. `filename` is a quoted ASCII fallback
+// for old clients, `filename*` contains the percent encoded UTF-8 name
+pub fn content_disposition_header(disposition: &ContentDisposition, file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = file_name
+        .bytes()
+        .map(|byte| {
+            if is_attr_char(byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_active_mimes() {
+        for mime in [
+            "text/html",
+            "TEXT/HTML; charset=utf-8",
+            "application/xhtml+xml",
+            "image/svg+xml",
+            "application/rdf+xml",
+            "application/vnd.ms-visio.drawing.main+xml",
+            "application/xml",
+            "text/javascript",
+            "application/ecmascript",
+        ] {
+            assert!(is_active_mime(mime), "{}", mime);
+        }
+        for mime in [
+            "text/plain",
+            "image/png",
+            "application/json",
+            "application/pdf",
+            "application/xml-dtd",
+            "",
+        ] {
+            assert!(!is_active_mime(mime), "{}", mime);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::io::Cursor;
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use poem::error::InternalServerError;
+use poem::http::{header, StatusCode};
+use poem::web::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, IfRange, LastModified};
+use poem::{Body, Request, Response, ResponseBuilder, Result};
+use tokio::io::AsyncReadExt;
+
+use crate::compression::decompress;
+use crate::crypto::{DecryptingReader, Key};
+use crate::models::File;
+use crate::negotiate::accepts_encoding;
+use crate::serve::DumpError;
+use crate::storage::{BlobReader, Storage};
+use crate::util::random_token;
+
+// Requests with more ranges are answered with the full file
+const MAX_RANGES: usize = 16;
+
+// Every range of a compressed file is decompressed from the start of the file
+const MAX_COMPRESSED_RANGES: usize = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+// Parses a `Range` header as described in RFC 9110, ignoring it if it is malformed
+pub fn parse_range(value: &str, size: u64, max_ranges: usize) -> RangeRequest {
+    let specs = match value.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (start, end) = match spec.trim().split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full,
+        };
+        let range = if start.is_empty() {
+            let length = match end.parse::<u64>() {
+                Ok(length) => length,
+                Err(_) => return RangeRequest::Full,
+            };
+            if length == 0 {
+                continue;
+            }
+            size.saturating_sub(length)..size
+        } else {
+            let start = match start.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end = if end.is_empty() {
+                size
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => (end + 1).min(size),
+                    _ => return RangeRequest::Full,
+                }
+            };
+            start..end
+        };
+        if range.start < size {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else if ranges.len() > max_ranges {
+        RangeRequest::Full
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+fn if_range_matches(req: &Request, etag: &ETag, last_modified: &LastModified) -> bool {
+    match req.headers().typed_get::<IfRange>() {
+        Some(if_range) => !if_range.is_modified(Some(etag), Some(last_modified)),
+        None => true,
+    }
+}
+
+// `If-None-Match` takes precedence over `If-Modified-Since`, see RFC 9110 section 13.2.2
+fn is_not_modified(req: &Request, etag: &ETag, last_modified: SystemTime) -> bool {
+    if let Some(if_none_match) = req.headers().typed_get::<IfNoneMatch>() {
+        !if_none_match.precondition_passes(etag)
+    } else if let Some(if_modified_since) = req.headers().typed_get::<IfModifiedSince>() {
+        !if_modified_since.is_modified(last_modified)
+    } else {
+        false
+    }
+}
+
+// Opens a range of the bytes in the storage
+async fn open_stored(
+    storage: &dyn Storage,
+    file: &File,
+    range: &Range<u64>,
+    content_key: Option<&Key>,
+) -> Result<BlobReader> {
+    let reader = storage
+        .open(&file.hash, range.clone())
+        .await
+        .map_err(InternalServerError)?;
+    Ok(match content_key {
+        Some(content_key) => Box::new(DecryptingReader::new(reader, content_key, range.start)),
+        None => Box::new(reader),
+    })
+}
+
+// Opens a range of the original file. Compressed files can not be seeked,
+// so they are decompressed from the start and the bytes before the range are skipped
+async fn open_range(
+    storage: &dyn Storage,
+    file: &File,
+    range: &Range<u64>,
+    content_key: Option<&Key>,
+) -> Result<BlobReader> {
+    let encoding = match &file.encoding {
+        Some(encoding) => encoding,
+        None => return open_stored(storage, file, range, content_key).await,
+    };
+    let reader = open_stored(storage, file, &(0..file.stored_size as u64), content_key).await?;
+    let mut reader = decompress(reader, encoding).map_err(InternalServerError)?;
+    tokio::io::copy(&mut (&mut reader).take(range.start), &mut tokio::io::sink())
+        .await
+        .map_err(InternalServerError)?;
+    Ok(Box::new(reader.take(range.end - range.start)))
+}
+
+fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+// Streams the stored file, honoring conditional, range and encoding headers of the request
+pub async fn file_response(
+    req: &Request,
+    builder: ResponseBuilder,
+    storage: &dyn Storage,
+    file: &File,
+    content_key: Option<&Key>,
+    allow_ranges: bool,
+) -> Result<Response> {
+    let size = file.size as u64;
+    // Without ranges, Range headers are ignored and the whole file is sent
+    let range_header = req.header(header::RANGE).filter(|_| allow_ranges);
+    let mime = file.mime.as_str();
+    // Compressed files are sent as they are stored if the client accepts their encoding.
+    // Ranges always refer to the original file, so they are served decompressed
+    let send_encoded = match &file.encoding {
+        Some(encoding) => range_header.is_none() && accepts_encoding(req, encoding),
+        None => false,
+    };
+    let etag = match &file.encoding {
+        Some(encoding) if send_encoded => file.encoded_etag(encoding),
+        _ => file.etag(),
+    };
+    // Hashes and encodings are valid entity tags, unless the database has been edited by hand
+    let etag = ETag::from_str(&etag).map_err(|_e| {
+        InternalServerError(DumpError::new(
+            "invalid_etag",
+            format!("Could not build the entity tag {}", etag),
+        ))
+    })?;
+    let modified = storage
+        .modified(&file.hash)
+        .await
+        .map_err(InternalServerError)?;
+    let last_modified = LastModified::from(modified);
+    let mut builder = builder
+        .header(
+            header::ACCEPT_RANGES,
+            if allow_ranges { "bytes" } else { "none" },
+        )
+        .typed_header(etag.clone())
+        .typed_header(last_modified);
+    if file.encoding.is_some() {
+        builder = builder.header(header::VARY, "accept-encoding");
+    }
+    if is_not_modified(req, &etag, modified) {
+        return Ok(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()));
+    }
+    if let (true, Some(encoding)) = (send_encoded, &file.encoding) {
+        let stored_size = file.stored_size as u64;
+        let reader = open_stored(storage, file, &(0..stored_size), content_key).await?;
+        return Ok(builder
+            .header(header::CONTENT_ENCODING, encoding.as_str())
+            .header(header::CONTENT_LENGTH, stored_size)
+            .content_type(mime)
+            .body(Body::from_async_read(reader)));
+    }
+
+    let range_request = match range_header {
+        Some(value) if if_range_matches(req, &etag, &last_modified) => {
+            let max_ranges = match file.encoding {
+                Some(_) => MAX_COMPRESSED_RANGES,
+                None => MAX_RANGES,
+            };
+            parse_range(value, size, max_ranges)
+        }
+        _ => RangeRequest::Full,
+    };
+    match range_request {
+        RangeRequest::Full => {
+            let reader = open_range(storage, file, &(0..size), content_key).await?;
+            Ok(builder
+                .header(header::CONTENT_LENGTH, size)
+                .content_type(mime)
+                .body(Body::from_async_read(reader)))
+        }
+        RangeRequest::Unsatisfiable => Ok(builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+            .body(Body::empty())),
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            let reader = open_range(storage, file, range, content_key).await?;
+            Ok(builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, content_range(range, size))
+                .header(header::CONTENT_LENGTH, range.end - range.start)
+                .content_type(mime)
+                .body(Body::from_async_read(reader)))
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = random_token();
+            let mut length = 0;
+            let mut reader: BlobReader = Box::new(tokio::io::empty());
+            for range in &ranges {
+                let part_header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    mime,
+                    content_range(range, size)
+                );
+                length += part_header.len() as u64 + range.end - range.start;
+                let part = open_range(storage, file, range, content_key).await?;
+                reader = Box::new(reader.chain(Cursor::new(part_header)).chain(part));
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            length += closing.len() as u64;
+            reader = Box::new(reader.chain(Cursor::new(closing)));
+            Ok(builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_LENGTH, length)
+                .content_type(format!("multipart/byteranges; boundary={}", boundary))
+                .body(Body::from_async_read(reader)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[Range<u64>]) -> RangeRequest {
+        RangeRequest::Partial(ranges.to_vec())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10, MAX_RANGES), partial(&[0..5]));
+        assert_eq!(parse_range("bytes=5-", 10, MAX_RANGES), partial(&[5..10]));
+        assert_eq!(
+            parse_range("bytes=5-100", 10, MAX_RANGES),
+            partial(&[5..10])
+        );
+        assert_eq!(
+            parse_range("bytes=0-1, 4-5", 10, MAX_RANGES),
+            partial(&[0..2, 4..6])
+        );
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-3", 10, MAX_RANGES), partial(&[7..10]));
+        assert_eq!(parse_range("bytes=-20", 10, MAX_RANGES), partial(&[0..10]));
+        assert_eq!(
+            parse_range("bytes=-0", 10, MAX_RANGES),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=-0, 0-0", 10, MAX_RANGES),
+            partial(&[0..1])
+        );
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(
+            parse_range("bytes=10-", 10, MAX_RANGES),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=20-30", 10, MAX_RANGES),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=20-30, 0-0", 10, MAX_RANGES),
+            partial(&[0..1])
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_ranges() {
+        for value in [
+            "bytes=5-2",
+            "bytes=a-b",
+            "bytes=5",
+            "bytes=0-1,x",
+            "bytes=--1",
+            "items=0-1",
+            "0-1",
+        ] {
+            assert_eq!(
+                parse_range(value, 10, MAX_RANGES),
+                RangeRequest::Full,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let ranges = (0..MAX_RANGES as u64)
+            .map(|start| format!("{}-{}", start, start))
+            .collect::<Vec<String>>();
+        let value = format!("bytes={}", ranges.join(","));
+        assert!(matches!(
+            parse_range(&value, 100, MAX_RANGES),
+            RangeRequest::Partial(ranges) if ranges.len() == MAX_RANGES
+        ));
+        let value = format!("{},{}-", value, MAX_RANGES);
+        assert_eq!(parse_range(&value, 100, MAX_RANGES), RangeRequest::Full);
+        assert_eq!(
+            parse_range("bytes=0-1,4-5", 100, MAX_COMPRESSED_RANGES),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn rejects_ranges_of_empty_files() {
+        for value in ["bytes=0-", "bytes=0-0", "bytes=-5"] {
+            assert_eq!(
+                parse_range(value, 0, MAX_RANGES),
+                RangeRequest::Unsatisfiable,
+                "{}",
+                value
+            );
+        }
+    }
+}
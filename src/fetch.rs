@@ -0,0 +1,311 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use futures_util::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use poem::error::{BadGateway, BadRequest, Forbidden, InternalServerError, PayloadTooLarge};
+use poem::Result;
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use tokio_util::io::StreamReader;
+
+use crate::opts::ServeArgs;
+use crate::serve::DumpError;
+use crate::upload::TempUpload;
+
+pub struct FetchOptions {
+    pub timeout: Duration,
+    pub max_redirects: usize,
+    pub max_size: usize,
+    // Allows fetching from loopback, private and link-local addresses
+    pub allow_private: bool,
+}
+
+impl FetchOptions {
+    pub fn from_args(args: &ServeArgs) -> FetchOptions {
+        FetchOptions {
+            timeout: args.url_fetch_timeout,
+            max_redirects: args.url_fetch_max_redirects,
+            max_size: args.max_size,
+            allow_private: args.url_fetch_allow_private,
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space of carrier grade NATs
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(&ipv4);
+    }
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local addresses
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local addresses
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, which may reach private IPv4 addresses
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+// Whether an address is reachable on the internet, the server must not be used to reach its
+// own network, e.g. cloud metadata services at 169.254.169.254
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+fn invalid_url(message: &str) -> poem::Error {
+    BadRequest(DumpError::new("invalid_url", message.to_string()))
+}
+
+fn fetch_failed(e: impl std::fmt::Display) -> poem::Error {
+    BadGateway(DumpError::new(
+        "fetch_failed",
+        format!("Could not fetch url: {}", e),
+    ))
+}
+
+// Resolves the host of the url and returns the address to connect to, after checking it
+async fn resolve_target(url: &Url, options: &FetchOptions) -> Result<SocketAddr> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid_url("Only http and https urls are supported"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid_url("The url has no host"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| invalid_url("The url has no port"))?;
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(fetch_failed)?
+        .collect();
+    // All addresses are checked, as the host could otherwise mix a public and a private address
+    if !options.allow_private && addresses.iter().any(|address| !is_public_ip(&address.ip())) {
+        return Err(Forbidden(DumpError::new(
+            "url_forbidden",
+            "Urls may not point to private addresses".to_string(),
+        )));
+    }
+    addresses
+        .into_iter()
+        .next()
+        .ok_or_else(|| fetch_failed("The host has no addresses"))
+}
+
+// The last path segment, e.g. `file%20name.txt` becomes `file name.txt`
+fn url_file_name(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|segments| segments.last())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+        .filter(|segment| !segment.is_empty() && !segment.chars().any(char::is_control))
+        .unwrap_or_else(|| "file".to_string())
+}
+
+// Downloads a remote file into the tmp directory and returns it together with its file name.
+// Redirects are followed manually, so every target is checked and the connection is pinned
+// to the checked address, which a second DNS lookup could otherwise change
+pub async fn fetch(
+    url: &str,
+    options: &FetchOptions,
+    data_directory: &Path,
+) -> Result<(String, TempUpload)> {
+    let mut url = Url::parse(url.trim()).map_err(|_e| invalid_url("Could not parse url"))?;
+    let mut redirects = 0;
+    let response = loop {
+        let address = resolve_target(&url, options).await?;
+        // A proxy from the environment would resolve the host itself, skipping the address check
+        let mut builder = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .redirect(Policy::none())
+            .no_proxy();
+        if let Some(domain) = url.domain() {
+            builder = builder.resolve(domain, address);
+        }
+        let client = builder.build().map_err(InternalServerError)?;
+        let response = client.get(url.clone()).send().await.map_err(fetch_failed)?;
+        if !response.status().is_redirection() {
+            break response.error_for_status().map_err(fetch_failed)?;
+        }
+        if redirects >= options.max_redirects {
+            return Err(fetch_failed("Too many redirects"));
+        }
+        redirects += 1;
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| fetch_failed("Redirect without a location"))?;
+        url = url.join(location).map_err(fetch_failed)?;
+    };
+    if let Some(content_length) = response.content_length() {
+        if content_length > options.max_size as u64 {
+            return Err(PayloadTooLarge(DumpError::too_large(options.max_size)));
+        }
+    }
+    let file_name = url_file_name(response.url());
+    let stream = response.bytes_stream().map_err(io::Error::other);
+    let reader = Box::pin(StreamReader::new(stream));
+    let upload = TempUpload::receive(reader, data_directory, options.max_size).await?;
+    Ok((file_name, upload))
+}
+
+pub async fn fetch_url(url: &str, state: &ServeArgs) -> Result<(String, TempUpload)> {
+    fetch(url, &FetchOptions::from_args(state), &state.data_directory).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::util::TestDirectory;
+
+    // Answers every connection with the given responses in turn, a minimal stand-in for a remote server
+    async fn serve_responses(responses: Vec<String>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _peer) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn ok_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn options(allow_private: bool) -> FetchOptions {
+        FetchOptions {
+            timeout: Duration::from_secs(5),
+            max_redirects: 2,
+            max_size: 1024,
+            allow_private,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_file_with_decoded_name() {
+        let address = serve_responses(vec![ok_response("hello")]).await;
+        let url = format!("http://{}/some%20file.txt", address);
+        let data_directory = TestDirectory::new("fetch");
+        let (file_name, upload) = fetch(&url, &options(true), &data_directory).await.unwrap();
+        assert_eq!(file_name, "some file.txt");
+        assert_eq!(upload.size, 5);
+        assert_eq!(std::fs::read(&upload.path).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let address = serve_responses(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /target.txt\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+            ok_response("redirected"),
+        ])
+        .await;
+        let url = format!("http://{}/start", address);
+        let data_directory = TestDirectory::new("redirect");
+        let (file_name, upload) = fetch(&url, &options(true), &data_directory).await.unwrap();
+        assert_eq!(file_name, "target.txt");
+        assert_eq!(upload.size, 10);
+    }
+
+    #[tokio::test]
+    async fn ignores_proxies_from_the_environment() {
+        let address = serve_responses(vec![ok_response("direct")]).await;
+        // Nothing listens on the discard port, requests through the proxy would fail
+        std::env::set_var("HTTP_PROXY", "http://127.0.0.1:9");
+        std::env::set_var("http_proxy", "http://127.0.0.1:9");
+        let url = format!("http://{}/direct.txt", address);
+        let data_directory = TestDirectory::new("proxy");
+        let (file_name, upload) = fetch(&url, &options(true), &data_directory).await.unwrap();
+        assert_eq!(file_name, "direct.txt");
+        assert_eq!(std::fs::read(&upload.path).unwrap(), b"direct");
+    }
+
+    #[tokio::test]
+    async fn rejects_private_addresses() {
+        let address = serve_responses(vec![ok_response("secret")]).await;
+        let url = format!("http://{}/file", address);
+        let result = fetch(&url, &options(false), &TestDirectory::new("private")).await;
+        assert_eq!(
+            result.err().unwrap().status(),
+            poem::http::StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn enforces_max_size() {
+        let body = "x".repeat(2048);
+        let address = serve_responses(vec![ok_response(&body)]).await;
+        let url = format!("http://{}/large", address);
+        let result = fetch(&url, &options(true), &TestDirectory::new("large")).await;
+        assert_eq!(
+            result.err().unwrap().status(),
+            poem::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use poem::error::{InternalServerError, TooManyRequests, Unauthorized};
+use poem::http::header;
+use poem::{async_trait, Endpoint, Middleware, Request, Result};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use rusqlite::{Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+
+use crate::db::{migrate_database, with_connection, DbPool};
+use crate::opts::{KeyLimits, ServeArgs};
+use crate::rate_limit::{client_identity, RateLimit};
+use crate::serve::DumpError;
+use crate::util::create_connection;
+
+const KEY_PREFIX: &str = "dump_";
+const KEY_LENGTH: usize = 32;
+
+// Keys are random enough to be stored as plain SHA-256 hashes, which can be looked up directly
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    KEY_PREFIX.to_string() + &Alphanumeric.sample_string(&mut OsRng, KEY_LENGTH)
+}
+
+#[derive(Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub revoked: Option<DateTime<Utc>>,
+    pub limits: KeyLimits,
+}
+
+impl ApiKey {
+    // The key hash in column 2 is never read
+    fn from_row(row: &Row) -> Result<ApiKey, rusqlite::Error> {
+        let allowed_groups: Option<String> = row.get(7)?;
+        Ok(ApiKey {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created: row.get(3)?,
+            revoked: row.get(4)?,
+            limits: KeyLimits {
+                max_size: row.get(5)?,
+                max_expires: row.get(6)?,
+                allowed_groups: allowed_groups
+                    .map(|groups| groups.split(',').map(ToString::to_string).collect())
+                    .unwrap_or_default(),
+                rate_limit_factor: row.get(8)?,
+                daily_upload_bytes: row.get(9)?,
+                daily_upload_files: row.get(10)?,
+            },
+        })
+    }
+
+    // Returns the id of the new key
+    pub fn create(
+        connection: &Connection,
+        name: &str,
+        key: &str,
+        limits: &KeyLimits,
+    ) -> Result<i64, rusqlite::Error> {
+        let allowed_groups = if limits.allowed_groups.is_empty() {
+            None
+        } else {
+            Some(limits.allowed_groups.join(","))
+        };
+        connection.execute(
+            "INSERT INTO api_keys (name, key_hash, created, max_size, max_expires, allowed_groups,
+               rate_limit_factor, daily_upload_bytes, daily_upload_files)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            (
+                name,
+                hash_key(key),
+                Utc::now(),
+                limits.max_size,
+                limits.max_expires,
+                allowed_groups,
+                limits.rate_limit_factor,
+                limits.daily_upload_bytes,
+                limits.daily_upload_files,
+            ),
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    // Revoked keys are not found
+    pub fn search_by_key(
+        connection: &Connection,
+        key: &str,
+    ) -> Result<Option<ApiKey>, rusqlite::Error> {
+        connection
+            .query_row(
+                "SELECT * FROM api_keys WHERE key_hash = ?1 AND revoked IS NULL",
+                (hash_key(key),),
+                ApiKey::from_row,
+            )
+            .optional()
+    }
+
+    // The active key behind an identity as recorded for uploaders, e.g. `key:3`
+    pub fn search_by_identity(
+        connection: &Connection,
+        identity: &str,
+    ) -> Result<Option<ApiKey>, rusqlite::Error> {
+        let id = match identity
+            .strip_prefix("key:")
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        connection
+            .query_row(
+                "SELECT * FROM api_keys WHERE id = ?1 AND revoked IS NULL",
+                (id,),
+                ApiKey::from_row,
+            )
+            .optional()
+    }
+
+    pub fn all(connection: &Connection) -> Result<Vec<ApiKey>, rusqlite::Error> {
+        connection
+            .prepare("SELECT * FROM api_keys ORDER BY id")?
+            .query_map([], ApiKey::from_row)?
+            .collect::<Result<Vec<ApiKey>, rusqlite::Error>>()
+    }
+
+    // Returns false if there is no active key with this id
+    pub fn revoke(connection: &Connection, id: i64) -> Result<bool, rusqlite::Error> {
+        connection
+            .execute(
+                "UPDATE api_keys SET revoked = ?1 WHERE id = ?2 AND revoked IS NULL",
+                (Utc::now(), id),
+            )
+            .map(|changed| changed > 0)
+    }
+
+    // Rate limits and daily limits are accounted to the key instead of the client address
+    pub fn identity(&self) -> String {
+        format!("key:{}", self.id)
+    }
+
+    pub fn apply(&self, args: &ServeArgs) -> ServeArgs {
+        let mut args = args.clone();
+        if let Some(max_size) = self.limits.max_size {
+            args.max_size = max_size;
+        }
+        if let Some(max_expires) = self.limits.max_expires {
+            args.max_expires = max_expires;
+            args.min_expires = args.min_expires.min(max_expires);
+        }
+        args.blocked_groups
+            .retain(|group| !self.limits.allowed_groups.contains(group));
+        if self.limits.daily_upload_bytes.is_some() {
+            args.daily_upload_bytes = self.limits.daily_upload_bytes;
+        }
+        if self.limits.daily_upload_files.is_some() {
+            args.daily_upload_files = self.limits.daily_upload_files;
+        }
+        args
+    }
+}
+
+pub fn request_api_key(req: &Request) -> Option<&ApiKey> {
+    req.extensions().get::<ApiKey>()
+}
+
+// The settings of the instance with the overrides of the API key of the request
+pub fn request_settings(req: &Request, args: &Arc<ServeArgs>) -> Arc<ServeArgs> {
+    match request_api_key(req) {
+        Some(key) => Arc::new(key.apply(args)),
+        None => args.clone(),
+    }
+}
+
+// Attaches the API key passed via `Authorization: Bearer` to the request. Other authorization
+// schemes are left alone, as basic auth carries the passwords of downloads.
+// Invalid keys count against the client address, which is refused once its budget is used up
+pub struct ApiKeyAuth {
+    failures: RateLimit,
+}
+
+impl ApiKeyAuth {
+    pub fn new(failures: RateLimit) -> ApiKeyAuth {
+        ApiKeyAuth { failures }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for ApiKeyAuth {
+    type Output = ApiKeyAuthImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ApiKeyAuthImpl(self.failures.clone(), ep)
+    }
+}
+
+pub struct ApiKeyAuthImpl<E>(RateLimit, E);
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for ApiKeyAuthImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let key = req
+            .header(header::AUTHORIZATION)
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(|key| key.trim().to_string());
+        if let Some(key) = key {
+            let client = client_identity(&req);
+            if self.0.exhausted(&client) {
+                return Err(TooManyRequests(DumpError::new(
+                    "rate_limited",
+                    "Too many invalid API keys, please try again later".to_string(),
+                )));
+            }
+            let pool = req.data::<DbPool>().cloned().ok_or_else(|| {
+                InternalServerError(DumpError::new("database", "No database".to_string()))
+            })?;
+            match with_connection(&pool, move |conn| ApiKey::search_by_key(conn, &key)).await? {
+                Some(api_key) => {
+                    req.extensions_mut().insert(api_key);
+                }
+                None => {
+                    self.0.record(&client);
+                    return Err(Unauthorized(DumpError::new(
+                        "invalid_api_key",
+                        "Invalid or revoked API key".to_string(),
+                    )));
+                }
+            }
+        }
+        self.1.call(req).await
+    }
+}
+
+fn format_limit<T: Display>(name: &str, value: Option<T>) -> Option<String> {
+    value.map(|value| format!("{}={}", name, value))
+}
+
+pub async fn keys_add(data_directory: PathBuf, name: String, limits: KeyLimits) {
+    if limits
+        .rate_limit_factor
+        .map_or(false, |factor| factor <= 0.0)
+    {
+        panic!("The rate limit factor must be larger than 0");
+    }
+    migrate_database(&data_directory);
+    let connection = create_connection(&data_directory).expect("Could not create connection");
+    let key = generate_key();
+    let id = ApiKey::create(&connection, &name, &key, &limits).expect("Could not create key");
+    println!("Created key {} ({})", id, name);
+    println!("{}", key);
+}
+
+pub async fn keys_list(data_directory: PathBuf) {
+    migrate_database(&data_directory);
+    let connection = create_connection(&data_directory).expect("Could not create connection");
+    for key in ApiKey::all(&connection).expect("Could not list keys") {
+        let state = match key.revoked {
+            Some(revoked) => format!("revoked {}", revoked.to_rfc3339()),
+            None => "active".to_string(),
+        };
+        let allowed_groups = if key.limits.allowed_groups.is_empty() {
+            None
+        } else {
+            Some(key.limits.allowed_groups.join(","))
+        };
+        let limits: Vec<String> = [
+            format_limit("max_size", key.limits.max_size),
+            format_limit("max_expires", key.limits.max_expires),
+            format_limit("allowed_groups", allowed_groups),
+            format_limit("rate_limit_factor", key.limits.rate_limit_factor),
+            format_limit("daily_upload_bytes", key.limits.daily_upload_bytes),
+            format_limit("daily_upload_files", key.limits.daily_upload_files),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        println!(
+            "{:>4} {:<20} created {} {} {}",
+            key.id,
+            key.name,
+            key.created.to_rfc3339(),
+            state,
+            limits.join(" ")
+        );
+    }
+}
+
+pub async fn keys_revoke(data_directory: PathBuf, id: i64) {
+    migrate_database(&data_directory);
+    let connection = create_connection(&data_directory).expect("Could not create connection");
+    if ApiKey::revoke(&connection, id).expect("Could not revoke key") {
+        println!("Revoked key {}", id);
+    } else {
+        println!("No active key with id {}", id);
+    }
+}
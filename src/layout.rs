@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use crate::storage::{is_blob_id, sharded_path};
+
+// Moves files stored in the flat layout of older versions into the sharded layout.
+// Every file is moved atomically, so an interrupted migration can simply be run again.
+// Files which are not named like blobs are left alone.
+pub async fn migrate_layout(data_directory: PathBuf) {
+    let files_directory = data_directory.join("files");
+    std::fs::create_dir_all(&files_directory).expect("Could not create files directory");
+    let entries = std::fs::read_dir(&files_directory).expect("Could not read files directory");
+    let mut moved = 0;
+    let mut duplicates = 0;
+    let mut skipped = 0;
+    for entry in entries {
+        let entry = entry.expect("Could not read directory entry");
+        if !entry
+            .file_type()
+            .expect("Could not read file type")
+            .is_file()
+        {
+            continue;
+        }
+        let id = entry.file_name().to_string_lossy().to_string();
+        if !is_blob_id(&id) {
+            skipped += 1;
+            continue;
+        }
+        let target = sharded_path(&files_directory, &id);
+        if target == entry.path() {
+            continue;
+        }
+        if target.exists() {
+            // The file has been uploaded again after the server switched to the sharded layout
+            std::fs::remove_file(entry.path()).expect("Could not delete duplicate file");
+            duplicates += 1;
+            continue;
+        }
+        std::fs::create_dir_all(target.parent().unwrap()).expect("Could not create directory");
+        std::fs::rename(entry.path(), &target).expect("Could not move file");
+        moved += 1;
+    }
+    println!("Moved {} files", moved);
+    if duplicates > 0 {
+        println!(
+            "Deleted {} files which already existed in the new layout",
+            duplicates
+        );
+    }
+    if skipped > 0 {
+        println!("Skipped {} files which are not blobs", skipped);
+    }
+}
@@ -6,11 +6,27 @@ use serve::serve;
 
 mod block_list;
 mod clean;
+mod client_ip;
+mod compression;
+mod crypto;
+mod db;
+mod disposition;
+mod download;
+mod fetch;
+mod keys;
+mod layout;
 mod mime;
 mod models;
+mod negotiate;
 mod opts;
+mod quota;
+mod rate_limit;
+mod reconcile;
 mod serve;
 mod stats;
+mod storage;
+mod token;
+mod upload;
 mod util;
 
 use tracing_subscriber;
@@ -20,9 +36,27 @@ async fn main() {
     tracing_subscriber::fmt().compact().init();
     let cli: Cli = opts::Cli::parse();
     match cli.command {
-        opts::Commands::Clean { data_directory } => clean(data_directory).await,
+        opts::Commands::Clean(args) => clean(args).await,
         opts::Commands::Serve(args) => serve(args).await,
+        opts::Commands::MigrateLayout { data_directory } => {
+            layout::migrate_layout(data_directory).await
+        }
         opts::Commands::Stats { data_directory } => stats::stats(data_directory).await,
+        opts::Commands::Db { command } => match command {
+            opts::DbCommands::Migrate { data_directory } => db::db_migrate(data_directory).await,
+            opts::DbCommands::Status { data_directory } => db::db_status(data_directory).await,
+        },
+        opts::Commands::Keys { command } => match command {
+            opts::KeysCommands::Add {
+                data_directory,
+                name,
+                limits,
+            } => keys::keys_add(data_directory, name, limits).await,
+            opts::KeysCommands::List { data_directory } => keys::keys_list(data_directory).await,
+            opts::KeysCommands::Revoke { data_directory, id } => {
+                keys::keys_revoke(data_directory, id).await
+            }
+        },
         opts::Commands::Generate { shell } => {
             let mut cmd = Cli::command_for_update();
             print_completions(shell, &mut cmd);
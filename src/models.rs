@@ -1,48 +1,153 @@
+use std::collections::HashSet;
 use std::path::PathBuf;
 
-use crate::{mime::identify, util::random_token};
+use crate::{
+    mime::identify,
+    quota::{quota_window_start, DailyLimits, UploadUsage},
+    upload::TempUpload,
+    util::{hash_token, verify_password},
+};
 use chrono::{DateTime, TimeDelta, Utc};
-use rusqlite::{Connection, OptionalExtension};
-use sha256::digest;
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
 
 pub struct DumpDetails {
     pub file_name: String,
     pub secret: Option<String>,
     pub expires: TimeDelta,
+    pub max_downloads: Option<u32>,
+    pub password: Option<String>,
+    // A custom token requested by the uploader
+    pub token: Option<String>,
+    pub content_disposition: Option<String>,
 }
 
 pub struct Dump {
-    pub file_bytes: Vec<u8>,
+    pub upload: TempUpload,
     pub details: DumpDetails,
 }
 
+pub enum UploadOutcome {
+    Committed,
+    QuotaExceeded,
+    DailyLimitExceeded,
+    TokenTaken,
+    // The file has been deleted since it was found, but its blob has not been written again
+    FileMissing,
+}
+
+// Other constraint violations, e.g. of the file reference, are errors
+fn is_token_taken(error: &rusqlite::Error) -> bool {
+    match error {
+        rusqlite::Error::SqliteFailure(error, Some(message)) => {
+            (error.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                || error.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+                && message.contains("urls.token")
+        }
+        _ => false,
+    }
+}
+
+// Creates the rows of an upload in a single transaction. The write lock is taken up front,
+// so concurrent uploads can not both pass the quota checks
+pub fn commit_upload(
+    connection: &Connection,
+    file: &File,
+    url: &Url,
+    blob_written: bool,
+    disk_quota: usize,
+    daily_limits: DailyLimits,
+) -> Result<UploadOutcome, rusqlite::Error> {
+    let transaction = Transaction::new_unchecked(connection, TransactionBehavior::Immediate)?;
+    if let Some(uploader) = &url.uploader {
+        Upload::prune(&transaction, uploader, quota_window_start())?;
+        let usage = Upload::usage_since(&transaction, uploader, quota_window_start())?;
+        if !daily_limits.permits(&usage, file.size) {
+            return Ok(UploadOutcome::DailyLimitExceeded);
+        }
+    }
+    if File::search_file_by_hash(&transaction, &file.hash)?.is_none() {
+        if !blob_written {
+            return Ok(UploadOutcome::FileMissing);
+        }
+        if File::stored_size_sum(&transaction)? + file.stored_size > disk_quota {
+            return Ok(UploadOutcome::QuotaExceeded);
+        }
+        file.create(&transaction)?;
+    }
+    match url.create(&transaction) {
+        Err(error) if is_token_taken(&error) => return Ok(UploadOutcome::TokenTaken),
+        result => result?,
+    }
+    if let Some(uploader) = &url.uploader {
+        Upload::record(&transaction, uploader, file.size)?;
+    }
+    transaction.commit()?;
+    Ok(UploadOutcome::Committed)
+}
+
+#[derive(Clone)]
 pub struct File {
+    // The content hash, or the blob id for encrypted files
     pub hash: String,
     pub size: usize,
     pub mime: String,
     pub group: String,
+    pub encrypted: bool,
+    // The number of bytes in the storage, which differs from the size of compressed files
+    pub stored_size: usize,
+    // The content coding of compressed files, e.g. `zstd`
+    pub encoding: Option<String>,
 }
 
 impl File {
-    pub fn new(hash: String, size: usize, mime: String, group: String) -> File {
+    pub fn new(
+        hash: String,
+        size: usize,
+        mime: String,
+        group: String,
+        encrypted: bool,
+        stored_size: usize,
+        encoding: Option<String>,
+    ) -> File {
         File {
             hash,
             size,
             mime,
             group,
+            encrypted,
+            stored_size,
+            encoding,
         }
     }
 
+    // Files stored by older versions have no stored size, as they are stored uncompressed
+    fn from_row(row: &Row) -> Result<File, rusqlite::Error> {
+        let size = row.get(1)?;
+        let stored_size: Option<usize> = row.get(5)?;
+        Ok(File::new(
+            row.get(0)?,
+            size,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            stored_size.unwrap_or(size),
+            row.get(6)?,
+        ))
+    }
+
     pub fn from_dump(dump: &Dump, data_directory: &PathBuf) -> File {
-        let hash = digest(&dump.file_bytes);
-        let size = dump.file_bytes.len();
+        let hash = dump.upload.hash.clone();
+        let size = dump.upload.size;
 
-        let (_label, mime, group) = identify(data_directory, &dump.file_bytes);
+        let (_label, mime, group) = identify(data_directory, &dump.upload.sample);
         File {
             size,
             hash,
             mime,
             group,
+            encrypted: false,
+            stored_size: size,
+            encoding: None,
         }
     }
 
@@ -73,62 +178,72 @@ impl File {
         })
     }
 
-    pub fn register_table(conn: &Connection) {
-        let _ = conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS files (
-          hash TEXT PRIMARY KEY,
-          size INTEGER NOT NULL,
-          mime TEXT,
-          file_type TEXT
-        )",
-                (),
-            )
-            .unwrap();
+    // The disk usage of all files, used for the quota
+    pub fn stored_size_sum(conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.query_row(
+            "SELECT SUM(COALESCE(stored_size, size)) FROM files",
+            [],
+            |row| {
+                let value: Option<u64> = row.get(0)?;
+                Ok(value.unwrap_or(0) as usize)
+            },
+        )
     }
+
     pub fn search_file_by_hash(
         connection: &Connection,
         hash: &str,
     ) -> Result<Option<File>, rusqlite::Error> {
         connection
-            .query_row("SELECT * FROM files WHERE hash = ?1", (hash,), |row| {
-                Ok(File::new(
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                ))
-            })
+            .query_row(
+                "SELECT * FROM files WHERE hash = ?1",
+                (hash,),
+                File::from_row,
+            )
             .optional()
     }
 
     pub fn create(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
         connection
             .execute(
-                "INSERT INTO files VALUES(?1, ?2, ?3, ?4)",
+                "INSERT INTO files VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
                 (
                     self.hash.clone(),
                     self.size,
                     self.mime.clone(),
                     self.group.clone(),
+                    self.encrypted,
+                    self.stored_size,
+                    self.encoding.clone(),
                 ),
             )
             .map(|_| ())
     }
 
-    pub fn write(&self, data_dir: PathBuf, bytes: Vec<u8>) -> Result<(), std::io::Error> {
-        let file_dir = data_dir.join("files");
-        if !file_dir.exists() {
-            std::fs::create_dir_all(&file_dir)?;
-        }
-        let file_path = file_dir.join(&self.hash);
-        std::fs::write(file_path, &bytes)
+    // Strong entity tag derived from the content hash
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.hash)
+    }
+
+    // The compressed representation of a file needs a different entity tag
+    pub fn encoded_etag(&self, encoding: &str) -> String {
+        format!("\"{}-{}\"", self.hash, encoding)
+    }
+    pub fn hashes(connection: &Connection) -> Result<HashSet<String>, rusqlite::Error> {
+        connection
+            .prepare("SELECT hash FROM files")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, rusqlite::Error>>()
     }
 
-    pub fn read(&self, data_dir: PathBuf) -> Result<Vec<u8>, std::io::Error> {
-        let file_path = data_dir.join("files").join(&self.hash);
-        std::fs::read(file_path)
+    // Removes a file together with all of its urls
+    pub fn delete_with_urls(connection: &Connection, hash: &str) -> Result<(), rusqlite::Error> {
+        let transaction = Transaction::new_unchecked(connection, TransactionBehavior::Immediate)?;
+        transaction.execute("DELETE FROM urls WHERE file_hash = ?1", (hash,))?;
+        transaction.execute("DELETE FROM files WHERE hash = ?1", (hash,))?;
+        transaction.commit()
     }
+
     pub fn delete_unlinked(connection: &Connection) -> Result<(), rusqlite::Error> {
         connection
             .execute(
@@ -141,35 +256,64 @@ impl File {
     pub fn search_unlinked(connection: &Connection) -> Result<Vec<File>, rusqlite::Error> {
         connection
             .prepare("SELECT * FROM files WHERE NOT EXISTS (SELECT 1 FROM urls WHERE files.hash = urls.file_hash)")?
-            .query_map([], |row| {
-                Ok(File::new(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-            })?
+            .query_map([], File::from_row)?
             .collect::<Result<Vec<File>, rusqlite::Error>>()
     }
 }
 
+#[derive(Clone)]
 pub struct Url {
-    pub token: String,
+    // The `token` column only contains the hash of the token
+    pub token_hash: String,
     pub file_hash: String,
-    pub secret: String,
+    // Delete secrets are only stored as argon2 hashes
+    pub secret_hash: String,
     pub expires: DateTime<Utc>,
     pub file_name: String,
+    // None if the url may be downloaded until it expires
+    pub remaining_downloads: Option<u32>,
+    pub password_hash: Option<String>,
+    // The encryption mode and the wrapped content key of encrypted files
+    pub encryption: Option<String>,
+    pub wrapped_key: Option<Vec<u8>>,
+    pub downloads: u32,
+    // Overrides the content disposition of the server
+    pub content_disposition: Option<String>,
+    // The identity the upload is accounted to, None for urls created by older versions
+    pub uploader: Option<String>,
+    pub created: Option<DateTime<Utc>>,
 }
 
 impl Url {
     pub fn new(
-        token: String,
+        token_hash: String,
         file_hash: String,
-        secret: String,
+        secret_hash: String,
         expires: DateTime<Utc>,
         file_name: String,
+        remaining_downloads: Option<u32>,
+        password_hash: Option<String>,
+        encryption: Option<String>,
+        wrapped_key: Option<Vec<u8>>,
+        downloads: u32,
+        content_disposition: Option<String>,
+        uploader: Option<String>,
+        created: Option<DateTime<Utc>>,
     ) -> Self {
         Url {
             file_hash,
-            token,
-            secret,
+            token_hash,
+            secret_hash,
             expires,
             file_name,
+            remaining_downloads,
+            password_hash,
+            encryption,
+            wrapped_key,
+            downloads,
+            content_disposition,
+            uploader,
+            created,
         }
     }
 
@@ -177,48 +321,49 @@ impl Url {
         conn.query_row("SELECT COUNT(1) FROM urls", [], |row| Ok(row.get(0)?))
     }
 
-    pub fn from_dump_details_and_file(dump: &DumpDetails, file: &File) -> Url {
-        let token = random_token();
-        let secret = if dump.secret.is_some() {
-            dump.secret.clone().unwrap()
-        } else {
-            random_token()
-        };
+    pub fn from_dump_details_and_file(
+        dump: &DumpDetails,
+        file: &File,
+        token: &str,
+        secret_hash: String,
+        password_hash: Option<String>,
+    ) -> Url {
         let expires = Utc::now() + dump.expires;
         Self::new(
-            token,
+            hash_token(token),
             file.hash.clone(),
-            secret,
+            secret_hash,
             expires,
             dump.file_name.clone(),
+            dump.max_downloads,
+            password_hash,
+            None,
+            None,
+            0,
+            dump.content_disposition.clone(),
+            None,
+            Some(Utc::now()),
         )
     }
 
-    pub fn register_table(conn: &Connection) {
-        let _ = conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS urls (
-          token TEXT PRIMARY KEY,
-          file_hash TEXT,
-          secret TEXT,
-          expires TEXT NOT NULL,
-          file_name TEXT NOT NULL,
-          FOREIGN KEY(file_hash) REFERENCES files(hash)
-        )",
-                (),
-            )
-            .unwrap();
-    }
     pub fn create(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
         connection
             .execute(
-                "INSERT INTO urls VALUES(?1, ?2, ?3, ?4, ?5)",
+                "INSERT INTO urls VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
                 (
-                    self.token.clone(),
+                    self.token_hash.clone(),
                     self.file_hash.clone(),
-                    self.secret.clone(),
+                    self.secret_hash.clone(),
                     self.expires.clone(),
                     self.file_name.clone(),
+                    self.remaining_downloads,
+                    self.password_hash.clone(),
+                    self.encryption.clone(),
+                    self.wrapped_key.clone(),
+                    self.downloads,
+                    self.content_disposition.clone(),
+                    self.uploader.clone(),
+                    self.created,
                 ),
             )
             .map(|_| ())
@@ -226,7 +371,31 @@ impl Url {
 
     pub fn delete(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
         connection
-            .execute("DELETE FROM urls WHERE token = ?1", (&self.token,))
+            .execute("DELETE FROM urls WHERE token = ?1", (&self.token_hash,))
+            .map(|_| ())
+    }
+
+    // Writes the fields which may be changed by the owner of the url
+    pub fn update(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection
+            .execute(
+                "UPDATE urls SET expires = ?1, file_name = ?2, content_disposition = ?3 WHERE token = ?4",
+                (
+                    self.expires,
+                    &self.file_name,
+                    &self.content_disposition,
+                    &self.token_hash,
+                ),
+            )
+            .map(|_| ())
+    }
+
+    pub fn record_download(&self, connection: &Connection) -> Result<(), rusqlite::Error> {
+        connection
+            .execute(
+                "UPDATE urls SET downloads = downloads + 1 WHERE token = ?1",
+                (&self.token_hash,),
+            )
             .map(|_| ())
     }
 
@@ -235,25 +404,72 @@ impl Url {
         token: &str,
     ) -> Result<Option<Url>, rusqlite::Error> {
         connection
-            .query_row("SELECT * FROM urls WHERE token = ?1", (token,), |row| {
-                Ok(Url::new(
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                ))
-            })
+            .query_row(
+                "SELECT * FROM urls WHERE token = ?1",
+                (hash_token(token),),
+                |row| {
+                    Ok(Url::new(
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                        row.get(6)?,
+                        row.get(7)?,
+                        row.get(8)?,
+                        row.get(9)?,
+                        row.get(10)?,
+                        row.get(11)?,
+                        row.get(12)?,
+                    ))
+                },
+            )
             .optional()
     }
+
     pub fn file(&self, connection: &Connection) -> Result<File, rusqlite::Error> {
         File::search_file_by_hash(&connection, &self.file_hash).map(Option::unwrap)
     }
 
+    // Atomically counts a download, returns the number of remaining downloads or None if there are none left
+    pub fn consume_download(
+        &self,
+        connection: &Connection,
+    ) -> Result<Option<u32>, rusqlite::Error> {
+        connection
+            .query_row(
+                "UPDATE urls SET remaining_downloads = remaining_downloads - 1, downloads = downloads + 1
+                 WHERE token = ?1 AND remaining_downloads > 0
+                 RETURNING remaining_downloads",
+                (&self.token_hash,),
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    // The comparison of argon2 hashes takes constant time
+    pub fn check_secret(&self, secret: &str) -> bool {
+        verify_password(secret, &self.secret_hash)
+    }
+
+    // Urls without a password may be downloaded by anyone
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        match (&self.password_hash, password) {
+            (None, _) => true,
+            (Some(password_hash), Some(password)) => verify_password(password, password_hash),
+            (Some(_), None) => false,
+        }
+    }
+
     pub fn expired(&self) -> bool {
         self.expires < Utc::now()
     }
 
+    pub fn remaining_lifetime(&self) -> TimeDelta {
+        (self.expires - Utc::now()).max(TimeDelta::zero())
+    }
+
     pub fn delete_expired(connection: &Connection) -> Result<(), rusqlite::Error> {
         let expires = Utc::now();
         connection
@@ -270,3 +486,65 @@ impl Url {
         )
     }
 }
+
+// The uploads of an identity within the daily window. Rows outlive their urls, so deleting
+// a url does not reset the usage of its uploader
+pub struct Upload;
+
+impl Upload {
+    // Every upload counts with the size of its file, even if the content was already stored
+    pub fn record(
+        connection: &Connection,
+        uploader: &str,
+        size: usize,
+    ) -> Result<(), rusqlite::Error> {
+        connection
+            .execute(
+                "INSERT INTO uploads (uploader, created, size) VALUES(?1, ?2, ?3)",
+                (uploader, Utc::now(), size),
+            )
+            .map(|_| ())
+    }
+
+    pub fn usage_since(
+        connection: &Connection,
+        uploader: &str,
+        since: DateTime<Utc>,
+    ) -> Result<UploadUsage, rusqlite::Error> {
+        connection.query_row(
+            "SELECT COUNT(*), SUM(size) FROM uploads WHERE uploader = ?1 AND created > ?2",
+            (uploader, since),
+            |row| {
+                let bytes: Option<u64> = row.get(1)?;
+                Ok(UploadUsage {
+                    files: row.get(0)?,
+                    bytes: bytes.unwrap_or(0) as usize,
+                })
+            },
+        )
+    }
+
+    // Removes the uploads of an identity which no longer count towards its usage
+    pub fn prune(
+        connection: &Connection,
+        uploader: &str,
+        before: DateTime<Utc>,
+    ) -> Result<(), rusqlite::Error> {
+        connection
+            .execute(
+                "DELETE FROM uploads WHERE uploader = ?1 AND created <= ?2",
+                (uploader, before),
+            )
+            .map(|_| ())
+    }
+
+    // Removes the old uploads of all identities, including those which stopped uploading
+    pub fn prune_all(
+        connection: &Connection,
+        before: DateTime<Utc>,
+    ) -> Result<(), rusqlite::Error> {
+        connection
+            .execute("DELETE FROM uploads WHERE created <= ?1", (before,))
+            .map(|_| ())
+    }
+}
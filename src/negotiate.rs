@@ -0,0 +1,97 @@
+use poem::http::header;
+use poem::web::Json;
+use poem::{async_trait, Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use serde::Serialize;
+
+use crate::serve::{DumpError, PasswordRequired};
+
+pub fn wants_json(req: &Request) -> bool {
+    req.header(header::ACCEPT)
+        .map_or(false, |accept| accept.contains("application/json"))
+}
+
+// Checks whether `Accept-Encoding` contains the content coding without a quality of 0
+pub fn accepts_encoding(req: &Request, encoding: &str) -> bool {
+    req.header(header::ACCEPT_ENCODING).map_or(false, |accept| {
+        accept.split(',').any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let rejected = parts.any(|parameter| {
+                parameter
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.trim().parse::<f32>().ok())
+                    .map_or(false, |quality| quality == 0.0)
+            });
+            name.eq_ignore_ascii_case(encoding) && !rejected
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+pub fn error_code(error: &poem::Error) -> String {
+    if let Some(dump_error) = error.downcast_ref::<DumpError>() {
+        return dump_error.code().to_string();
+    }
+    if let Some(password_required) = error.downcast_ref::<PasswordRequired>() {
+        return password_required.0.code().to_string();
+    }
+    error
+        .status()
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_lowercase()
+        .replace(' ', "_")
+}
+
+// Renders errors as JSON objects for clients which accept JSON
+pub struct JsonErrors;
+
+impl<E: Endpoint> Middleware<E> for JsonErrors {
+    type Output = JsonErrorsImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        JsonErrorsImpl(ep)
+    }
+}
+
+pub struct JsonErrorsImpl<E>(E);
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for JsonErrorsImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let json = wants_json(&req);
+        match self.0.call(req).await {
+            Ok(output) => Ok(output.into_response()),
+            Err(error) if json => {
+                let body = ErrorBody {
+                    code: error_code(&error),
+                    message: error.to_string().trim_end().to_string(),
+                };
+                let status = error.status();
+                // Errors may ask for credentials, which the JSON response must keep doing
+                let www_authenticate = error
+                    .into_response()
+                    .headers()
+                    .get(header::WWW_AUTHENTICATE)
+                    .cloned();
+                let mut response = Json(body).into_response();
+                response.set_status(status);
+                if let Some(www_authenticate) = www_authenticate {
+                    response
+                        .headers_mut()
+                        .insert(header::WWW_AUTHENTICATE, www_authenticate);
+                }
+                Ok(response)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
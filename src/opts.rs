@@ -1,10 +1,8 @@
-use std::{fmt::Display, io, path::PathBuf, time::Duration};
-
-use crate::util::create_connection;
+use std::{fmt::Display, io, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
 
 use clap::{Args, Command, Parser, Subcommand, ValueEnum};
 use clap_complete::{generate, Generator, Shell};
-use rusqlite::Connection;
+use ipnet::IpNet;
 use serde::Serialize;
 
 #[derive(Parser)]
@@ -19,27 +17,111 @@ pub fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
 
 #[derive(Subcommand)]
 pub enum Commands {
-    Clean {
+    Clean(CleanArgs),
+    Stats {
         #[arg(short, long, env)]
         data_directory: PathBuf,
     },
-    Stats {
+    Serve(ServeArgs),
+    MigrateLayout {
         #[arg(short, long, env)]
         data_directory: PathBuf,
     },
-    Serve(ServeArgs),
+
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommands,
+    },
 
     Generate {
         shell: Shell,
     },
 }
 
+#[derive(Subcommand)]
+pub enum DbCommands {
+    // Applies all pending schema migrations
+    Migrate {
+        #[arg(short, long, env)]
+        data_directory: PathBuf,
+    },
+    // Shows the schema version and the pending migrations
+    Status {
+        #[arg(short, long, env)]
+        data_directory: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommands {
+    // Creates an API key, which is only shown once
+    Add {
+        #[arg(short, long, env)]
+        data_directory: PathBuf,
+
+        name: String,
+
+        #[command(flatten)]
+        limits: KeyLimits,
+    },
+    List {
+        #[arg(short, long, env)]
+        data_directory: PathBuf,
+    },
+    // Revoked keys are kept, as uploads are accounted to them
+    Revoke {
+        #[arg(short, long, env)]
+        data_directory: PathBuf,
+
+        id: i64,
+    },
+}
+
+// Overrides of the limits of the instance for uploads with an API key, unset limits are inherited
+#[derive(Args, Clone, Default)]
+pub struct KeyLimits {
+    #[arg(long)]
+    pub max_size: Option<usize>,
+
+    #[arg(long)]
+    pub max_expires: Option<usize>,
+
+    // Groups which may be uploaded although they are blocked
+    #[arg(long, num_args = 0.., value_delimiter = ',')]
+    pub allowed_groups: Vec<String>,
+
+    // Multiplies all rate limits
+    #[arg(long, value_parser = parse_rate_limit_factor)]
+    pub rate_limit_factor: Option<f64>,
+
+    #[arg(long)]
+    pub daily_upload_bytes: Option<usize>,
+
+    #[arg(long)]
+    pub daily_upload_files: Option<usize>,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub enum ContentDisposition {
     Inline,
     Attachment,
 }
 
+impl ContentDisposition {
+    pub fn from_name(name: &str) -> Option<ContentDisposition> {
+        match name {
+            "inline" => Some(ContentDisposition::Inline),
+            "attachment" => Some(ContentDisposition::Attachment),
+            _ => None,
+        }
+    }
+}
+
 impl Display for ContentDisposition {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -51,11 +133,8 @@ impl Display for ContentDisposition {
 
 impl ValueEnum for ContentDisposition {
     fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
-        match input {
-            "inline" => Ok(ContentDisposition::Inline),
-            "attachment" => Ok(ContentDisposition::Attachment),
-            _ => Err(format!("Invalid value for ContentDisposition: {}", input)),
-        }
+        ContentDisposition::from_name(input)
+            .ok_or(format!("Invalid value for ContentDisposition: {}", input))
     }
 
     fn value_variants<'a>() -> &'a [Self] {
@@ -70,10 +149,265 @@ impl ValueEnum for ContentDisposition {
     }
 }
 
+// Token mode derives the key protecting a file from a key in its link, master mode uses the master key
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Encryption {
+    None,
+    Token,
+    Master,
+}
+
+impl Encryption {
+    pub fn from_name(name: &str) -> Option<Encryption> {
+        match name {
+            "none" => Some(Encryption::None),
+            "token" => Some(Encryption::Token),
+            "master" => Some(Encryption::Master),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encryption::None => write!(f, "none"),
+            Encryption::Token => write!(f, "token"),
+            Encryption::Master => write!(f, "master"),
+        }
+    }
+}
+
+impl ValueEnum for Encryption {
+    fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
+        Encryption::from_name(input).ok_or(format!("Invalid value for Encryption: {}", input))
+    }
+
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Encryption::None, Encryption::Token, Encryption::Master]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            Encryption::None => Some(clap::builder::PossibleValue::new("none")),
+            Encryption::Token => Some(clap::builder::PossibleValue::new("token")),
+            Encryption::Master => Some(clap::builder::PossibleValue::new("master")),
+        }
+    }
+}
+
+// Files of the compressed groups are stored compressed with this algorithm
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Compression {
+    None,
+    Zstd,
+    Brotli,
+}
+
+impl Compression {
+    // The name of the algorithm in `Content-Encoding` headers
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+            Compression::Brotli => Some("br"),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Brotli => write!(f, "brotli"),
+        }
+    }
+}
+
+impl ValueEnum for Compression {
+    fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
+        match input {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "brotli" => Ok(Compression::Brotli),
+            _ => Err(format!("Invalid value for Compression: {}", input)),
+        }
+    }
+
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Compression::None, Compression::Zstd, Compression::Brotli]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            Compression::None => Some(clap::builder::PossibleValue::new("none")),
+            Compression::Zstd => Some(clap::builder::PossibleValue::new("zstd")),
+            Compression::Brotli => Some(clap::builder::PossibleValue::new("brotli")),
+        }
+    }
+}
+
+// The header which trusted proxies pass the client address in. Only one is read, as proxies
+// usually pass the others on unchanged, which would let clients choose their address
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyHeader {
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+}
+
+impl ProxyHeader {
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            ProxyHeader::Forwarded => "Forwarded",
+            ProxyHeader::XForwardedFor => "X-Forwarded-For",
+            ProxyHeader::XRealIp => "X-Real-IP",
+        }
+    }
+}
+
+impl Display for ProxyHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyHeader::Forwarded => write!(f, "forwarded"),
+            ProxyHeader::XForwardedFor => write!(f, "x-forwarded-for"),
+            ProxyHeader::XRealIp => write!(f, "x-real-ip"),
+        }
+    }
+}
+
+impl ValueEnum for ProxyHeader {
+    fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
+        match input {
+            "forwarded" => Ok(ProxyHeader::Forwarded),
+            "x-forwarded-for" => Ok(ProxyHeader::XForwardedFor),
+            "x-real-ip" => Ok(ProxyHeader::XRealIp),
+            _ => Err(format!("Invalid value for ProxyHeader: {}", input)),
+        }
+    }
+
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            ProxyHeader::Forwarded,
+            ProxyHeader::XForwardedFor,
+            ProxyHeader::XRealIp,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            ProxyHeader::Forwarded => Some(clap::builder::PossibleValue::new("forwarded")),
+            ProxyHeader::XForwardedFor => {
+                Some(clap::builder::PossibleValue::new("x-forwarded-for"))
+            }
+            ProxyHeader::XRealIp => Some(clap::builder::PossibleValue::new("x-real-ip")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageBackend {
+    FileSystem,
+    S3,
+    Memory,
+}
+
+impl Display for StorageBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageBackend::FileSystem => write!(f, "fs"),
+            StorageBackend::S3 => write!(f, "s3"),
+            StorageBackend::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl ValueEnum for StorageBackend {
+    fn from_str(input: &str, _ignore_case: bool) -> Result<Self, String> {
+        match input {
+            "fs" => Ok(StorageBackend::FileSystem),
+            "s3" => Ok(StorageBackend::S3),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("Invalid value for StorageBackend: {}", input)),
+        }
+    }
+
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            StorageBackend::FileSystem,
+            StorageBackend::S3,
+            StorageBackend::Memory,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            StorageBackend::FileSystem => Some(clap::builder::PossibleValue::new("fs")),
+            StorageBackend::S3 => Some(clap::builder::PossibleValue::new("s3")),
+            StorageBackend::Memory => Some(clap::builder::PossibleValue::new("memory")),
+        }
+    }
+}
+
+// Credentials of the S3 backend are read from the AWS_* environment variables
+#[derive(Args, Clone)]
+pub struct StorageArgs {
+    #[arg(long, env, default_value_t = StorageBackend::FileSystem)]
+    pub storage: StorageBackend,
+
+    #[arg(long, env)]
+    pub s3_bucket: Option<String>,
+
+    #[arg(long, env)]
+    pub s3_endpoint: Option<String>,
+
+    #[arg(long, env)]
+    pub s3_region: Option<String>,
+
+    #[arg(long, env)]
+    pub s3_allow_http: bool,
+}
+
+#[derive(Args)]
+pub struct CleanArgs {
+    #[arg(short, long, env)]
+    pub data_directory: PathBuf,
+
+    // Removes files, and their urls, whose blob is missing from the storage
+    #[arg(long, env)]
+    pub prune_missing: bool,
+
+    // Removes blobs unknown to the database from an S3 bucket, see `removes_orphans`
+    #[arg(long, env)]
+    pub prune_orphans: bool,
+
+    #[command(flatten)]
+    pub storage: StorageArgs,
+}
+
 fn parse_duration(s: &str) -> Result<Duration, cyborgtime::DurationError> {
     cyborgtime::parse_duration(s)
 }
 
+// Accepts CIDR ranges and single addresses
+pub fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    IpNet::from_str(s)
+        .or_else(|_e| IpAddr::from_str(s).map(IpNet::from))
+        .map_err(|_e| format!("Invalid IP address or CIDR range: {}", s))
+}
+
+// A factor of zero would leave a key without any requests, and stop its buckets from refilling
+pub fn parse_rate_limit_factor(s: &str) -> Result<f64, String> {
+    match f64::from_str(s) {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        _ => Err(format!(
+            "Invalid rate limit factor, expected a positive number: {}",
+            s
+        )),
+    }
+}
+
 #[derive(Args, Clone, Serialize)]
 pub struct ServeArgs {
 
@@ -97,6 +431,21 @@ pub struct ServeArgs {
     #[arg(long, env, default_value_t = 256*1024*1024)]
     pub max_size: usize,
 
+    // Limits multipart uploads, which may contain several files. A single file of `max_size`
+    // is always accepted
+    #[arg(long, env, default_value_t = 20)]
+    pub max_files_per_upload: usize,
+
+    #[arg(long, env, default_value_t = 1024*1024*1024)]
+    pub max_upload_size: usize,
+
+    // Limits the bytes and files each client may upload within 24 hours
+    #[arg(long, env)]
+    pub daily_upload_bytes: Option<usize>,
+
+    #[arg(long, env)]
+    pub daily_upload_files: Option<usize>,
+
     #[arg(long, env, default_value_t = 30 * 24 * 60 * 60 * 1000)]
     pub min_expires: usize,
 
@@ -106,22 +455,130 @@ pub struct ServeArgs {
     #[arg(long, env, value_parser, num_args = 0.., value_delimiter = ',', default_values_t = vec!["executable".to_string()])]
     pub blocked_groups: Vec<String>,
 
+    // IP lists contain one address or CIDR range per line and are reloaded when they change
     #[serde(skip_serializing)]
     #[arg(long, env)]
     pub blocked_ips: Option<PathBuf>,
 
+    // Removes files, and their urls, whose blob is missing from the storage on startup
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub prune_missing: bool,
+
+    // Removes blobs unknown to the database from an S3 bucket on startup
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub prune_orphans: bool,
+
+    // Proxies which may pass the client address via `proxy_header`
+    #[serde(skip_serializing)]
+    #[arg(long, env, value_parser = parse_ip_net, num_args = 0.., value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
+    #[serde(skip_serializing)]
+    #[arg(long, env, default_value_t = ProxyHeader::XForwardedFor)]
+    pub proxy_header: ProxyHeader,
+
+    // Only blocks uploads
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub blocked_upload_ips: Option<PathBuf>,
+
+    // Only clients in this list may access the instance
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub allowed_ips: Option<PathBuf>,
+
+    // Only clients in this list may upload
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub allowed_upload_ips: Option<PathBuf>,
+
     #[arg(long, env, default_value_t = ContentDisposition::Inline)]
     pub content_disposition: ContentDisposition,
 
-    #[arg(long, env, default_value_t = 1)]
+    // Files of these groups are always downloaded instead of being displayed
+    #[arg(long, env, value_parser, num_args = 0.., value_delimiter = ',', default_values_t = vec!["code".to_string()])]
+    pub attachment_groups: Vec<String>,
+
+    // Requests per client within `rate_limit_duration`, used by routes without their own budget
+    #[arg(long, env, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
     pub rate_limit_count: u64,
 
-    #[arg(long, env, default_value = "5s", value_parser=parse_duration)]
+    #[arg(long, env, default_value = "1m", value_parser=parse_duration)]
     pub rate_limit_duration: Duration,
-}
 
-impl ServeArgs {
-    pub fn create_connection(&self) -> Result<Connection, rusqlite::Error> {
-        create_connection(&self.data_directory)
-    }
+    #[arg(long, env, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_rate_limit_count: u64,
+
+    #[arg(long, env, default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..))]
+    pub download_rate_limit_count: u64,
+
+    #[arg(long, env, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub delete_rate_limit_count: u64,
+
+    // Requests with an invalid API key, which would otherwise allow guessing keys at full speed
+    #[arg(long, env, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_key_failure_rate_limit_count: u64,
+
+    // Number of clients tracked per budget, idle clients are forgotten first
+    #[arg(long, env, default_value_t = 100000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rate_limit_max_clients: u64,
+
+    // Only clients with an API key may upload
+    #[arg(long, env)]
+    pub require_api_key: bool,
+
+    // Allows the server to download files passed via the `url` field
+    #[arg(long, env)]
+    pub allow_url_uploads: bool,
+
+    #[arg(long, env, default_value = "30s", value_parser=parse_duration)]
+    pub url_fetch_timeout: Duration,
+
+    #[arg(long, env, default_value_t = 5)]
+    pub url_fetch_max_redirects: usize,
+
+    // Allows fetching urls which point to loopback, private or link-local addresses
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub url_fetch_allow_private: bool,
+
+    #[arg(long, env, default_value_t = Encryption::None)]
+    pub encryption: Encryption,
+
+    // 32 hex encoded bytes, required by the master encryption mode
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub master_key: Option<String>,
+
+    #[serde(skip_serializing)]
+    #[command(flatten)]
+    pub storage: StorageArgs,
+
+    #[arg(long, env, default_value_t = 10, value_parser = clap::value_parser!(u8).range(4..))]
+    pub token_length: u8,
+
+    // Characters used by generated tokens, only letters, digits, `-` and `_` are allowed.
+    // Defaults to upper and lower case letters and digits
+    #[serde(skip_serializing)]
+    #[arg(long, env)]
+    pub token_alphabet: Option<String>,
+
+    // Words which must not appear in generated or custom tokens
+    #[serde(skip_serializing)]
+    #[arg(long, env, value_parser, num_args = 0.., value_delimiter = ',')]
+    pub token_blocklist: Vec<String>,
+
+    #[arg(long, env, default_value_t = 8)]
+    pub db_pool_size: u32,
+
+    #[arg(long, env, default_value = "5s", value_parser=parse_duration)]
+    pub db_busy_timeout: Duration,
+
+    #[arg(long, env, default_value_t = Compression::None)]
+    pub compression: Compression,
+
+    #[arg(long, env, value_parser, num_args = 0.., value_delimiter = ',', default_values_t = vec!["text".to_string(), "code".to_string()])]
+    pub compress_groups: Vec<String>,
 }
@@ -0,0 +1,46 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use poem::Request;
+use serde::Serialize;
+
+use crate::opts::ServeArgs;
+use crate::rate_limit::rate_limit_identity;
+
+// Uploads are accounted to the same identity as the rate limits
+pub fn uploader_identity(req: &Request) -> String {
+    rate_limit_identity(req)
+}
+
+// The daily limits apply to a sliding window of 24 hours
+pub fn quota_window_start() -> DateTime<Utc> {
+    Utc::now() - TimeDelta::days(1)
+}
+
+// The files and bytes uploaded by an identity within the window
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct UploadUsage {
+    pub files: usize,
+    pub bytes: usize,
+}
+
+// None means unlimited
+#[derive(Clone, Copy)]
+pub struct DailyLimits {
+    pub bytes: Option<usize>,
+    pub files: Option<usize>,
+}
+
+impl DailyLimits {
+    pub fn from_args(args: &ServeArgs) -> DailyLimits {
+        DailyLimits {
+            bytes: args.daily_upload_bytes,
+            files: args.daily_upload_files,
+        }
+    }
+
+    // Whether another file of `size` bytes may be uploaded
+    pub fn permits(&self, usage: &UploadUsage, size: usize) -> bool {
+        self.bytes
+            .map_or(true, |bytes| usage.bytes.saturating_add(size) <= bytes)
+            && self.files.map_or(true, |files| usage.files < files)
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use poem::http::{header, HeaderValue, StatusCode};
+use poem::web::Json;
+use poem::{async_trait, Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use serde::Serialize;
+
+use crate::client_ip::client_ip;
+use crate::keys::request_api_key;
+use crate::negotiate::wants_json;
+
+// The key of the bucket of a request. Requests with an API key share the bucket of the key
+pub fn rate_limit_identity(req: &Request) -> String {
+    match request_api_key(req) {
+        Some(key) => key.identity(),
+        None => client_identity(req),
+    }
+}
+
+// IPv6 clients usually control a whole /64 network
+pub fn client_identity(req: &Request) -> String {
+    match client_ip(req) {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+        None => "unknown".to_string(),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    // API keys may have a larger capacity than the limiter
+    capacity: f64,
+    updated: Instant,
+    // Position in the recency order, unique even for buckets updated at the same instant
+    sequence: u64,
+}
+
+// The buckets with an index from their last use to their identity, so the least recently
+// used bucket is found without scanning all clients
+#[derive(Default)]
+struct Buckets {
+    by_identity: HashMap<String, Bucket>,
+    by_use: BTreeMap<u64, String>,
+    next_sequence: u64,
+}
+
+enum Decision {
+    Allowed { remaining: u64, reset: Duration },
+    Limited { retry_after: Duration },
+}
+
+// Token buckets which hold up to `capacity` requests and refill completely within `period`
+pub struct RateLimiter {
+    capacity: f64,
+    period: Duration,
+    max_clients: usize,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    fn refill_rate(&self, capacity: f64) -> f64 {
+        capacity / self.period.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_rate(bucket.capacity)).min(bucket.capacity)
+    }
+
+    // Drops the least recently used bucket, which is the most likely one to be refilled already
+    fn evict(&self, buckets: &mut Buckets) {
+        if let Some((_sequence, identity)) = buckets.by_use.pop_first() {
+            buckets.by_identity.remove(&identity);
+        }
+    }
+
+    // Whether the bucket of the identity is empty, without taking a token
+    fn exhausted(&self, identity: &str) -> bool {
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .by_identity
+            .get(identity)
+            .map_or(false, |bucket| self.refilled(bucket, Instant::now()) < 1.0)
+    }
+
+    fn acquire(&self, identity: &str, capacity: f64) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        if !buckets.by_identity.contains_key(identity)
+            && buckets.by_identity.len() >= self.max_clients
+        {
+            self.evict(buckets);
+        }
+        let sequence = buckets.next_sequence;
+        buckets.next_sequence += 1;
+        let bucket = buckets
+            .by_identity
+            .entry(identity.to_string())
+            .or_insert(Bucket {
+                tokens: capacity,
+                capacity,
+                updated: now,
+                sequence,
+            });
+        buckets.by_use.remove(&bucket.sequence);
+        buckets.by_use.insert(sequence, identity.to_string());
+        bucket.sequence = sequence;
+        // The limits of a key may have changed since the bucket was created
+        bucket.capacity = capacity;
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        let refill_rate = self.refill_rate(capacity);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed {
+                remaining: bucket.tokens.floor() as u64,
+                reset: Duration::from_secs_f64((capacity - bucket.tokens) / refill_rate),
+            }
+        } else {
+            Decision::Limited {
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / refill_rate),
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RateLimitedBody {
+    code: &'static str,
+    message: String,
+}
+
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+#[derive(Clone)]
+pub struct RateLimit(Arc<RateLimiter>);
+
+impl RateLimit {
+    pub fn new(capacity: u64, period: Duration, max_clients: usize) -> RateLimit {
+        RateLimit(Arc::new(RateLimiter {
+            capacity: capacity as f64,
+            period,
+            max_clients,
+            buckets: Mutex::new(Buckets::default()),
+        }))
+    }
+
+    // For limits on events which are only known after handling a request, e.g. failed logins,
+    // `exhausted` is checked before and `record` takes a token after the event
+    pub fn exhausted(&self, identity: &str) -> bool {
+        self.0.exhausted(identity)
+    }
+
+    pub fn record(&self, identity: &str) {
+        self.0.acquire(identity, self.0.capacity);
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimit {
+    type Output = RateLimitImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitImpl(self.0.clone(), ep)
+    }
+}
+
+pub struct RateLimitImpl<E>(Arc<RateLimiter>, E);
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for RateLimitImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let factor = request_api_key(&req)
+            .and_then(|key| key.limits.rate_limit_factor)
+            .unwrap_or(1.0);
+        let capacity = self.0.capacity * factor;
+        let limit = HeaderValue::from(capacity as u64);
+        match self.0.acquire(&rate_limit_identity(&req), capacity) {
+            Decision::Limited { retry_after } => {
+                let message = "Too many requests, please try again later".to_string();
+                let response = if wants_json(&req) {
+                    Json(RateLimitedBody {
+                        code: "rate_limited",
+                        message,
+                    })
+                    .into_response()
+                } else {
+                    (message + "\n").into_response()
+                };
+                let mut response = response
+                    .with_status(StatusCode::TOO_MANY_REQUESTS)
+                    .into_response();
+                let headers = response.headers_mut();
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds(retry_after)));
+                headers.insert("RateLimit-Limit", limit);
+                headers.insert("RateLimit-Remaining", HeaderValue::from(0));
+                headers.insert("RateLimit-Reset", HeaderValue::from(seconds(retry_after)));
+                Ok(response)
+            }
+            Decision::Allowed { remaining, reset } => {
+                let mut response = self.1.call(req).await?.into_response();
+                let headers = response.headers_mut();
+                headers.insert("RateLimit-Limit", limit);
+                headers.insert("RateLimit-Remaining", HeaderValue::from(remaining));
+                headers.insert("RateLimit-Reset", HeaderValue::from(seconds(reset)));
+                Ok(response)
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use poem::error::InternalServerError;
+use poem::Result;
+
+use crate::db::{with_connection, DbPool};
+use crate::models::File;
+use crate::serve::DumpError;
+use crate::storage::{is_blob_id, Storage};
+
+// Blobs and temporary uploads younger than this may belong to an upload which is still in progress
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Default)]
+pub struct ReconcileReport {
+    pub stale_uploads: usize,
+    pub missing_blobs: usize,
+    // Whether the files with missing blobs have been removed
+    pub pruned_missing: bool,
+    pub orphaned_blobs: usize,
+    // Whether the orphaned blobs have been removed
+    pub pruned_orphans: bool,
+}
+
+fn is_stale(modified: SystemTime) -> bool {
+    modified
+        .elapsed()
+        .map_or(false, |elapsed| elapsed > GRACE_PERIOD)
+}
+
+async fn remove_stale_uploads(data_directory: &Path) -> Result<usize> {
+    let mut removed = 0;
+    let mut entries = match tokio::fs::read_dir(data_directory.join("tmp")).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(0),
+    };
+    while let Some(entry) = entries.next_entry().await.map_err(InternalServerError)? {
+        let metadata = entry.metadata().await.map_err(InternalServerError)?;
+        if metadata.is_file() && is_stale(metadata.modified().map_err(InternalServerError)?) {
+            tokio::fs::remove_file(entry.path())
+                .await
+                .map_err(InternalServerError)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Repairs the state left behind by interrupted uploads: temporary uploads which were never
+// stored and blobs which were never committed to the database. Files whose blob is missing are
+// only reported, unless `prune_missing` is set, as a misconfigured or unmounted storage would
+// otherwise lose all urls. Orphaned blobs are only reported as well, unless `prune_orphans` is set
+pub async fn reconcile(
+    data_directory: &Path,
+    storage: &dyn Storage,
+    pool: &DbPool,
+    prune_missing: bool,
+    prune_orphans: bool,
+) -> Result<ReconcileReport> {
+    let mut report = ReconcileReport {
+        stale_uploads: remove_stale_uploads(data_directory).await?,
+        ..Default::default()
+    };
+    // The hashes are read before the blobs are listed, as blobs are written before their rows
+    let hashes = with_connection(pool, File::hashes).await?;
+    let blobs = storage.list().await.map_err(InternalServerError)?;
+    let blob_ids = blobs
+        .iter()
+        .map(|(id, _modified)| id.as_str())
+        .collect::<HashSet<&str>>();
+    let missing_hashes = hashes
+        .iter()
+        .filter(|hash| !blob_ids.contains(hash.as_str()))
+        .cloned()
+        .collect::<Vec<String>>();
+    report.missing_blobs = missing_hashes.len();
+    if prune_missing && !missing_hashes.is_empty() {
+        if blobs.is_empty() {
+            return Err(InternalServerError(DumpError::new(
+                "storage_empty",
+                format!(
+                    "Refusing to remove {} files, as the storage contains no blobs. Is the storage configured correctly?",
+                    missing_hashes.len()
+                ),
+            )));
+        }
+        for hash in missing_hashes {
+            with_connection(pool, move |conn| File::delete_with_urls(conn, &hash)).await?;
+        }
+        report.pruned_missing = true;
+    }
+    // Other objects in a shared bucket are never touched
+    for (id, modified) in &blobs {
+        if is_blob_id(id) && !hashes.contains(id) && is_stale(*modified) {
+            if prune_orphans {
+                storage.delete(id).await.map_err(InternalServerError)?;
+            }
+            report.orphaned_blobs += 1;
+        }
+    }
+    report.pruned_orphans = prune_orphans;
+    Ok(report)
+}
+
+impl ReconcileReport {
+    pub fn log(&self) {
+        log::info!(
+            "Reconciled storage: removed {} stale uploads",
+            self.stale_uploads
+        );
+        if self.pruned_orphans {
+            log::info!("Removed {} orphaned blobs", self.orphaned_blobs);
+        } else if self.orphaned_blobs > 0 {
+            log::warn!(
+                "Found {} blobs unknown to the database, pass --prune-orphans to remove them",
+                self.orphaned_blobs
+            );
+        }
+        if self.pruned_missing {
+            log::info!("Removed {} files with missing blobs", self.missing_blobs);
+        } else if self.missing_blobs > 0 {
+            log::warn!(
+                "Found {} files with missing blobs, pass --prune-missing to remove them",
+                self.missing_blobs
+            );
+        }
+    }
+}
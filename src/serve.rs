@@ -1,40 +1,74 @@
-use crate::block_list::build_deny_ips;
-use crate::models::{Dump, DumpDetails, File};
-use crate::util::calculate_expires;
-use crate::{models::Url, opts::ServeArgs};
-use chrono::TimeDelta;
+use crate::block_list::build_ip_filters;
+use crate::client_ip::ResolveClientIp;
+use crate::compression::compress;
+use crate::crypto::{
+    blob_id, content_key, encrypt_in_place, generate_link_key, unwrap_key, wrap_key, wrapping_key,
+    Key,
+};
+use crate::db::{build_pool, migrate_database, with_connection, DbPool};
+use crate::disposition::{content_disposition_header, download_requested, effective_disposition};
+use crate::download::file_response;
+use crate::fetch::fetch_url;
+use crate::keys::{request_api_key, request_settings, ApiKey, ApiKeyAuth};
+use crate::models::{commit_upload, Dump, DumpDetails, File, Upload, UploadOutcome};
+use crate::negotiate::{error_code, wants_json, JsonErrors};
+use crate::quota::{quota_window_start, uploader_identity, DailyLimits, UploadUsage};
+use crate::rate_limit::RateLimit;
+use crate::reconcile::reconcile;
+use crate::storage::{build_storage, record_storage, removes_orphans, Storage};
+use crate::token::TokenGenerator;
+use crate::upload::TempUpload;
+use crate::util::{calculate_expires, hash_password, random_token, split_link};
+use crate::{
+    models::Url,
+    opts::{ContentDisposition, Encryption, ServeArgs},
+};
+use chrono::{TimeDelta, Utc};
 use cyborgtime::parse_duration;
-use poem::error::{Forbidden, InsufficientStorage, NotFoundError};
+use poem::error::{
+    Conflict, Forbidden, InsufficientStorage, NotFoundError, PayloadTooLarge, ResponseError,
+    ServiceUnavailable, TooManyRequests, Unauthorized,
+};
 use poem::http::{header, StatusCode};
-use poem::middleware::{CatchPanic, TowerLayerCompatExt};
+use poem::middleware::CatchPanic;
+use poem::web::headers::{authorization::Basic, Authorization, HeaderMapExt};
 use poem::web::Html;
 use poem::{
-    error::{BadRequest, InternalServerError, PayloadTooLarge},
+    error::{BadRequest, InternalServerError},
     get, handler,
     listener::TcpListener,
     middleware::AddData,
     post,
-    web::{Data, Json, Multipart, Path},
-    Body, EndpointExt, Response, Result, Route, Server,
+    web::{Data, Field, Json, Multipart, Path},
+    Body, EndpointExt, FromRequest, IntoResponse, Request, RequestBody, Response, Result, Route,
+    Server,
 };
+use serde::Serialize;
 use std::{error::Error, fmt::Display, sync::Arc};
-use tower::limit::RateLimitLayer;
+use tokio::io::AsyncReadExt;
 #[derive(Debug, Clone)]
 pub struct DumpError {
+    code: &'static str,
     message: String,
 }
 
 impl DumpError {
-    pub fn new(message: String) -> DumpError {
+    // The code is a machine readable identifier of the error, used by JSON responses
+    pub fn new(code: &'static str, message: String) -> DumpError {
         DumpError {
+            code,
             message: message + "\n",
         }
     }
-    fn too_large(max_size: usize, my_size: usize) -> DumpError {
-        DumpError::new(format!(
-            "File of size {} larger than the maximum of {}",
-            my_size, max_size
-        ))
+    pub fn too_large(max_size: usize) -> DumpError {
+        DumpError::new(
+            "file_too_large",
+            format!("File larger than the maximum of {}", max_size),
+        )
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
     }
 }
 
@@ -50,78 +84,108 @@ impl Error for DumpError {
     }
 }
 
-async fn dump_parse_multipart(mut multipart: Multipart, state: Arc<ServeArgs>) -> Result<Dump> {
-    let mut file_name: Option<String> = None;
-    let mut file_bytes: Option<Vec<u8>> = None;
-    let mut secret: Option<String> = None;
-    let mut passed_expires: Option<TimeDelta> = None;
+// Asks browsers for the password of a download via basic auth
+#[derive(Debug)]
+pub struct PasswordRequired(pub DumpError);
 
-    while let Ok(Some(field)) = multipart.next_field().await {
-        let name = field
-            .name()
-            .map(ToString::to_string)
-            .ok_or(BadRequest(DumpError::new(
-                "Could not read field name".to_string(),
-            )))?;
-        if name == "file" {
-            file_name = Some(field.file_name().map(ToString::to_string).ok_or(
-                poem::error::BadRequest(DumpError::new("Could not read file name".to_string())),
-            )?);
-            let bytes = match field.bytes().await {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(e.into()),
-            };
-            if bytes.len() > state.max_size {
-                return Err(PayloadTooLarge(DumpError::too_large(
-                    state.max_size,
-                    bytes.len(),
-                )));
-            }
-            file_bytes = Some(bytes);
-        } else if name == "secret" {
-            let secret_bytes = match field.bytes().await {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(e.into()),
-            };
+impl PasswordRequired {
+    fn new() -> PasswordRequired {
+        PasswordRequired(DumpError::new(
+            "password_required",
+            "A valid password is required to download this file".to_string(),
+        ))
+    }
+}
 
-            let secret_text = std::str::from_utf8(&secret_bytes)
-                .map_err(|_e| BadRequest(DumpError::new("Could not parse secret".to_string())))?
-                .to_string();
-            if !secret_text.is_empty() {
-                secret = Some(secret_text);
-            }
-        } else if name == "expires" {
-            let expires_bytes = match field.bytes().await {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(e.into()),
-            };
-            let expires_string = std::str::from_utf8(&expires_bytes)
-                .map_err(|_e| BadRequest(DumpError::new("Could not parse expires".to_string())))?
-                .to_string();
-            let expires_duration = parse_duration(&expires_string)
-                .map_err(|_e| BadRequest(DumpError::new("Could not parse expires".to_string())))?;
-            passed_expires =
-                Some(TimeDelta::from_std(expires_duration).map_err(|_e| {
-                    BadRequest(DumpError::new("Could not parse expires".to_string()))
-                })?);
-        }
+impl Display for PasswordRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
     }
-    if file_name.is_none() || file_bytes.is_none() {
-        return Err(BadRequest(DumpError::new(
-            "Missing file and file name".to_string(),
-        )));
+}
+
+impl Error for PasswordRequired {}
+
+impl ResponseError for PasswordRequired {
+    fn status(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
     }
-    let file_name = file_name.unwrap();
-    let file_bytes = file_bytes.unwrap();
+
+    fn as_response(&self) -> Response {
+        Response::builder()
+            .status(self.status())
+            .header(header::WWW_AUTHENTICATE, "Basic realm=\"dump\"")
+            .body(self.0.to_string())
+    }
+}
+
+fn parse_secret(secret_text: String) -> Option<String> {
+    if secret_text.is_empty() {
+        None
+    } else {
+        Some(secret_text)
+    }
+}
+
+fn parse_expires(expires_string: &str) -> Result<TimeDelta> {
+    let expires_duration = parse_duration(expires_string).map_err(|_e| {
+        BadRequest(DumpError::new(
+            "invalid_expires",
+            "Could not parse expires".to_string(),
+        ))
+    })?;
+    TimeDelta::from_std(expires_duration).map_err(|_e| {
+        BadRequest(DumpError::new(
+            "invalid_expires",
+            "Could not parse expires".to_string(),
+        ))
+    })
+}
+
+fn parse_max_downloads(max_downloads_string: &str) -> Result<u32> {
+    match max_downloads_string.trim().parse::<u32>() {
+        Ok(max_downloads) if max_downloads > 0 => Ok(max_downloads),
+        _ => Err(BadRequest(DumpError::new(
+            "invalid_max_downloads",
+            "The maximum number of downloads must be a number larger than 0".to_string(),
+        ))),
+    }
+}
+
+// Optional arguments of an upload
+struct DumpOptions {
+    secret: Option<String>,
+    passed_expires: Option<TimeDelta>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+    token: Option<String>,
+    disposition: Option<ContentDisposition>,
+}
+
+fn parse_disposition(disposition_string: &str) -> Result<ContentDisposition> {
+    ContentDisposition::from_name(disposition_string.trim()).ok_or_else(|| {
+        BadRequest(DumpError::new(
+            "invalid_disposition",
+            "The content disposition must be inline or attachment".to_string(),
+        ))
+    })
+}
+
+fn build_dump(
+    file_name: String,
+    upload: TempUpload,
+    options: &DumpOptions,
+    state: &ServeArgs,
+) -> Result<Dump> {
     let mut expires = calculate_expires(
-        file_bytes.len(),
+        upload.size,
         state.min_expires,
         state.max_expires,
         state.max_size,
     );
-    if let Some(passed_expires) = passed_expires {
+    if let Some(passed_expires) = options.passed_expires {
         if passed_expires <= TimeDelta::zero() {
             return Err(BadRequest(DumpError::new(
+                "invalid_expires",
                 "The expires duration must be larger than 0".to_string(),
             )));
         }
@@ -130,65 +194,715 @@ async fn dump_parse_multipart(mut multipart: Multipart, state: Arc<ServeArgs>) -
     Ok(Dump {
         details: DumpDetails {
             file_name,
-            secret,
+            secret: options.secret.clone(),
             expires,
+            max_downloads: options.max_downloads,
+            password: options.password.clone(),
+            token: options.token.clone(),
+            content_disposition: options.disposition.as_ref().map(ToString::to_string),
         },
-        file_bytes,
+        upload,
     })
 }
 
-#[handler]
-async fn dump_file_handler(multipart: Multipart, state: Data<&Arc<ServeArgs>>) -> Result<String> {
-    let dump = dump_parse_multipart(multipart, state.clone()).await?;
-    let file = File::from_dump(&dump, &state.data_directory);
-    let connection = state
-        .create_connection()
-        .map_err(|e| InternalServerError(e))?;
-    let found_file =
-        File::search_file_by_hash(&connection, &file.hash).map_err(|x| InternalServerError(x))?;
-    if found_file.is_none() {
-        let size_sum = File::size_sum(&connection).map_err(|x| InternalServerError(x))?;
-        if size_sum + dump.file_bytes.len() > state.disk_quota {
-            return Err(InsufficientStorage(DumpError::new(
-                "The quota has been exceeded".to_string(),
+// Multipart uploads may contain several files, which share the secret and the expires duration
+struct MultipartUpload {
+    dumps: Vec<Dump>,
+    collection: bool,
+}
+
+fn too_many_files(max_files: usize) -> poem::Error {
+    BadRequest(DumpError::new(
+        "too_many_files",
+        format!("Uploads may contain at most {} files", max_files),
+    ))
+}
+
+fn upload_too_large(max_upload_size: usize) -> poem::Error {
+    PayloadTooLarge(DumpError::new(
+        "upload_too_large",
+        format!(
+            "The files of an upload are larger than the maximum of {}",
+            max_upload_size
+        ),
+    ))
+}
+
+// Text fields only hold short options, longer ones are rejected instead of being buffered
+const MAX_FIELD_SIZE: u64 = 8 * 1024;
+
+async fn field_text(field: Field, code: &'static str, name: &str) -> Result<String> {
+    let mut text = Vec::new();
+    let read = Box::pin(field.into_async_read())
+        .take(MAX_FIELD_SIZE + 1)
+        .read_to_end(&mut text)
+        .await;
+    if read.is_err() {
+        return Err(BadRequest(DumpError::new(
+            code,
+            format!("Could not parse {}", name),
+        )));
+    }
+    if text.len() as u64 > MAX_FIELD_SIZE {
+        return Err(BadRequest(DumpError::new(
+            code,
+            format!("The {} is longer than {} bytes", name, MAX_FIELD_SIZE),
+        )));
+    }
+    String::from_utf8(text)
+        .map_err(|_e| BadRequest(DumpError::new(code, format!("Could not parse {}", name))))
+}
+
+// Files are checked against the limits of the whole upload while they are received,
+// so no more than the remaining bytes are written to the tmp directory
+async fn dump_parse_multipart(
+    mut multipart: Multipart,
+    state: Arc<ServeArgs>,
+) -> Result<MultipartUpload> {
+    let max_upload_size = state.max_upload_size.max(state.max_size);
+    let mut upload_size = 0;
+    let mut uploads: Vec<(String, TempUpload)> = Vec::new();
+    let mut options = DumpOptions {
+        secret: None,
+        passed_expires: None,
+        max_downloads: None,
+        password: None,
+        token: None,
+        disposition: None,
+    };
+    let mut collection = false;
+
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field
+            .name()
+            .map(ToString::to_string)
+            .ok_or(BadRequest(DumpError::new(
+                "invalid_multipart",
+                "Could not read field name".to_string(),
+            )))?;
+        if (name == "file" || name == "url") && uploads.len() >= state.max_files_per_upload {
+            return Err(too_many_files(state.max_files_per_upload));
+        }
+        if name == "file" {
+            let file_name =
+                field
+                    .file_name()
+                    .map(ToString::to_string)
+                    .ok_or(poem::error::BadRequest(DumpError::new(
+                        "invalid_file_name",
+                        "Could not read file name".to_string(),
+                    )))?;
+            let reader = Box::pin(field.into_async_read());
+            let remaining = max_upload_size - upload_size;
+            let upload = match TempUpload::receive(
+                reader,
+                &state.data_directory,
+                state.max_size.min(remaining),
+            )
+            .await
+            {
+                Err(error)
+                    if remaining < state.max_size
+                        && error.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+                {
+                    return Err(upload_too_large(max_upload_size))
+                }
+                result => result?,
+            };
+            upload_size += upload.size;
+            uploads.push((file_name, upload));
+        } else if name == "secret" {
+            let secret_text = field_text(field, "invalid_secret", "secret").await?;
+            options.secret = parse_secret(secret_text);
+        } else if name == "password" {
+            let password_text = field_text(field, "invalid_password", "password").await?;
+            options.password = parse_secret(password_text);
+        } else if name == "token" {
+            let token_text = field_text(field, "invalid_token", "token").await?;
+            options.token = parse_secret(token_text);
+        } else if name == "disposition" {
+            let disposition_string =
+                field_text(field, "invalid_disposition", "disposition").await?;
+            options.disposition = Some(parse_disposition(&disposition_string)?);
+        } else if name == "expires" {
+            let expires_string = field_text(field, "invalid_expires", "expires").await?;
+            options.passed_expires = Some(parse_expires(&expires_string)?);
+        } else if name == "max_downloads" {
+            let max_downloads_string =
+                field_text(field, "invalid_max_downloads", "max_downloads").await?;
+            options.max_downloads = Some(parse_max_downloads(&max_downloads_string)?);
+        } else if name == "url" {
+            if !state.allow_url_uploads {
+                return Err(Forbidden(DumpError::new(
+                    "url_uploads_disabled",
+                    "Uploading from urls is disabled".to_string(),
+                )));
+            }
+            let url = field_text(field, "invalid_url", "url").await?;
+            let (file_name, upload) = fetch_url(&url, &state).await?;
+            upload_size += upload.size;
+            if upload_size > max_upload_size {
+                return Err(upload_too_large(max_upload_size));
+            }
+            uploads.push((file_name, upload));
+        } else if name == "collection" {
+            let collection_text = field_text(field, "invalid_collection", "collection").await?;
+            collection = collection_text == "true";
+        }
+    }
+    if uploads.is_empty() {
+        return Err(BadRequest(DumpError::new(
+            "missing_file",
+            "Missing file and file name".to_string(),
+        )));
+    }
+    let dumps = uploads
+        .into_iter()
+        .map(|(file_name, upload)| build_dump(file_name, upload, &options, &state))
+        .collect::<Result<Vec<Dump>>>()?;
+    Ok(MultipartUpload { dumps, collection })
+}
+
+fn header_text(req: &Request, name: &str) -> Result<Option<String>> {
+    match req.headers().get(name) {
+        Some(value) => value
+            .to_str()
+            .map(|text| Some(text.to_string()))
+            .map_err(|_e| {
+                BadRequest(DumpError::new(
+                    "invalid_header",
+                    format!("Could not parse header {}", name),
+                ))
+            }),
+        None => Ok(None),
+    }
+}
+
+// Raw body uploads pass the optional arguments as headers
+async fn dump_parse_raw(
+    req: &Request,
+    body: Body,
+    file_name: String,
+    state: Arc<ServeArgs>,
+) -> Result<Dump> {
+    let options = DumpOptions {
+        secret: header_text(req, "X-Secret")?.and_then(parse_secret),
+        passed_expires: match header_text(req, "X-Expires")? {
+            Some(expires_string) => Some(parse_expires(&expires_string)?),
+            None => None,
+        },
+        max_downloads: match header_text(req, "X-Max-Downloads")? {
+            Some(max_downloads_string) => Some(parse_max_downloads(&max_downloads_string)?),
+            None => None,
+        },
+        password: header_text(req, "X-Password")?.and_then(parse_secret),
+        token: header_text(req, "X-Token")?.and_then(parse_secret),
+        disposition: match header_text(req, "X-Disposition")? {
+            Some(disposition_string) => Some(parse_disposition(&disposition_string)?),
+            None => None,
+        },
+    };
+    let upload = TempUpload::receive(
+        body.into_async_read(),
+        &state.data_directory,
+        state.max_size,
+    )
+    .await?;
+    build_dump(file_name, upload, &options, &state)
+}
+
+#[derive(Serialize)]
+struct UploadedFile {
+    token: String,
+    url: String,
+    delete_url: String,
+    secret: String,
+    expires: String,
+    file_name: String,
+    size: usize,
+    mime: String,
+    group: String,
+    hash: String,
+}
+
+impl UploadedFile {
+    fn to_text(&self) -> String {
+        format!("{}\n{}\n", self.url, self.delete_url)
+    }
+}
+
+// A file of a multipart upload which could not be stored
+#[derive(Serialize)]
+struct FailedFile {
+    file_name: String,
+    code: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct UploadManifest {
+    files: Vec<UploadedFile>,
+    failed: Vec<FailedFile>,
+    collection: Option<UploadedFile>,
+}
+
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+// Generated tokens are retried this many times if they are already taken
+const MAX_TOKEN_ATTEMPTS: usize = 5;
+
+fn quota_exceeded() -> poem::Error {
+    InsufficientStorage(DumpError::new(
+        "quota_exceeded",
+        "The quota has been exceeded".to_string(),
+    ))
+}
+
+fn daily_limit_exceeded() -> poem::Error {
+    TooManyRequests(DumpError::new(
+        "daily_limit_exceeded",
+        "The daily upload limit has been exceeded".to_string(),
+    ))
+}
+
+async fn store_dump(
+    dump: Dump,
+    uploader: &str,
+    state: &ServeArgs,
+    storage: &dyn Storage,
+    pool: &DbPool,
+    token_generator: &TokenGenerator,
+) -> Result<UploadedFile> {
+    if let Some(token) = &dump.details.token {
+        if !token_generator.is_valid_custom_token(token) {
+            return Err(BadRequest(DumpError::new(
+                "invalid_token",
+                "Tokens may only contain letters, digits, - and _".to_string(),
             )));
         }
+    }
+    // Rejects most uploads before they are processed, the limits are checked again when committing
+    let daily_limits = DailyLimits::from_args(state);
+    let identity = uploader.to_string();
+    let usage = with_connection(pool, move |conn| {
+        Upload::usage_since(conn, &identity, quota_window_start())
+    })
+    .await?;
+    if !daily_limits.permits(&usage, dump.upload.size) {
+        return Err(daily_limit_exceeded());
+    }
+    let mut file = File::from_dump(&dump, &state.data_directory);
+    let content_key = match state.encryption {
+        Encryption::None => None,
+        _ => Some(content_key(&file.hash)),
+    };
+    if let Some(content_key) = &content_key {
+        file.hash = blob_id(content_key);
+        file.encrypted = true;
+    }
+    let hash = file.hash.clone();
+    let found_file =
+        with_connection(pool, move |conn| File::search_file_by_hash(conn, &hash)).await?;
+    let is_new_file = found_file.is_none();
+    if is_new_file {
         if state.blocked_groups.contains(&file.group) {
             return Err(Forbidden(DumpError::new(
+                "file_type_blocked",
                 "This type of file is not allowed".to_string(),
             )));
         }
-        file.create(&connection)
-            .map_err(|x| InternalServerError(x))?;
-        file.write(state.data_directory.clone(), dump.file_bytes)
+        // Files are compressed before they are encrypted, as encrypted data does not compress
+        let mut upload = dump.upload;
+        if state.compress_groups.contains(&file.group) {
+            if let Some(compressed) =
+                compress(&upload, &state.compression, &state.data_directory).await?
+            {
+                file.encoding = state
+                    .compression
+                    .content_encoding()
+                    .map(ToString::to_string);
+                upload = compressed;
+            }
+        }
+        file.stored_size = upload.size;
+        // Rejects most uploads before they are written, the quota is checked again when committing
+        let stored_size_sum = with_connection(pool, File::stored_size_sum).await?;
+        if stored_size_sum + file.stored_size > state.disk_quota {
+            return Err(quota_exceeded());
+        }
+        if let Some(content_key) = &content_key {
+            encrypt_in_place(&upload.path, content_key)
+                .await
+                .map_err(InternalServerError)?;
+        }
+        // The blob is written before the rows are committed. A crash in between
+        // only leaves an orphaned blob, which is removed by the reconciliation
+        storage
+            .put(&file.hash, upload)
+            .await
             .map_err(|x| InternalServerError(x))?;
     }
-    let url = Url::from_dump_details_and_file(&dump.details, &file);
-    // TODO: fix duplicate tokens
-    url.create(&connection)
-        .map_err(|x| InternalServerError(x))?;
-    // if search_result
-    let mut access_url = state.url.clone();
-    if !access_url.ends_with('/') {
-        access_url.push('/');
+    let secret = dump.details.secret.clone().unwrap_or_else(random_token);
+    // Hashed once for all token attempts, off the async runtime as Argon2 is slow on purpose
+    let secret_to_hash = secret.clone();
+    let password = dump.details.password.clone();
+    let (secret_hash, password_hash) = tokio::task::spawn_blocking(move || {
+        (
+            hash_password(&secret_to_hash),
+            password.as_deref().map(hash_password),
+        )
+    })
+    .await
+    .map_err(InternalServerError)?;
+    // Only known to the uploader, the link carries it after the token
+    let link_key = match state.encryption {
+        Encryption::Token => Some(generate_link_key()),
+        _ => None,
+    };
+    let mut attempts = 0;
+    let (url, token) = loop {
+        let token = match &dump.details.token {
+            Some(token) => token.clone(),
+            None => token_generator.generate().ok_or_else(|| {
+                InternalServerError(DumpError::new(
+                    "token_exhausted",
+                    "Could not generate a token without blocked words".to_string(),
+                ))
+            })?,
+        };
+        let mut url = Url::from_dump_details_and_file(
+            &dump.details,
+            &file,
+            &token,
+            secret_hash.clone(),
+            password_hash.clone(),
+        );
+        url.uploader = Some(uploader.to_string());
+        if let Some(content_key) = &content_key {
+            let wrapping_key = wrapping_key(
+                &state.encryption,
+                link_key.as_deref().unwrap_or_default(),
+                state.master_key.as_deref(),
+            )
+            .map_err(|e| InternalServerError(DumpError::new("encryption", e.to_string())))?;
+            url.encryption = Some(state.encryption.to_string());
+            url.wrapped_key = Some(wrap_key(content_key, &wrapping_key));
+        }
+        let new_file = file.clone();
+        let new_url = url.clone();
+        let disk_quota = state.disk_quota;
+        let outcome = with_connection(pool, move |conn| {
+            commit_upload(
+                conn,
+                &new_file,
+                &new_url,
+                is_new_file,
+                disk_quota,
+                daily_limits,
+            )
+        })
+        .await?;
+        match outcome {
+            UploadOutcome::Committed => break (url, token),
+            // The blob may be shared with a concurrent upload of the same content,
+            // so it is left to the reconciliation
+            UploadOutcome::QuotaExceeded => return Err(quota_exceeded()),
+            UploadOutcome::DailyLimitExceeded => return Err(daily_limit_exceeded()),
+            UploadOutcome::TokenTaken if dump.details.token.is_some() => {
+                return Err(Conflict(DumpError::new(
+                    "token_taken",
+                    "The requested token is already taken".to_string(),
+                )))
+            }
+            UploadOutcome::TokenTaken if attempts < MAX_TOKEN_ATTEMPTS => attempts += 1,
+            UploadOutcome::TokenTaken => {
+                return Err(InternalServerError(DumpError::new(
+                    "token_exhausted",
+                    "Could not generate an unused token".to_string(),
+                )))
+            }
+            UploadOutcome::FileMissing => {
+                return Err(ServiceUnavailable(DumpError::new(
+                    "upload_conflict",
+                    "The file was removed during the upload, please try again".to_string(),
+                )))
+            }
+        }
+    };
+    let mut base_url = state.url.clone();
+    if !base_url.ends_with('/') {
+        base_url.push('/');
+    }
+    let mut access_url = base_url.clone() + &token;
+    if let Some(link_key) = &link_key {
+        access_url.push('.');
+        access_url.push_str(link_key);
     }
-    access_url.push_str(&url.token);
 
-    let mut delete_url = access_url.clone();
+    let mut delete_url = base_url + &token;
     delete_url.push('/');
-    delete_url.push_str(&url.secret);
-    Ok(access_url + "\n" + &delete_url + "\n")
+    delete_url.push_str(&secret);
+    Ok(UploadedFile {
+        token,
+        url: access_url,
+        delete_url,
+        secret,
+        expires: url.expires.to_rfc3339(),
+        file_name: url.file_name,
+        size: file.size,
+        mime: file.mime,
+        group: file.group,
+        hash: file.hash,
+    })
+}
+
+fn uploaded_file_response(req: &Request, uploaded_file: UploadedFile) -> Response {
+    if wants_json(req) {
+        Json(uploaded_file).into_response()
+    } else {
+        uploaded_file.to_text().into_response()
+    }
+}
+
+// A collection is a plain text file listing the access urls of all files of an upload
+async fn store_collection(
+    files: &[UploadedFile],
+    details: DumpDetails,
+    uploader: &str,
+    state: &ServeArgs,
+    storage: &dyn Storage,
+    pool: &DbPool,
+    token_generator: &TokenGenerator,
+) -> Result<UploadedFile> {
+    let listing: String = files.iter().map(|file| file.url.clone() + "\n").collect();
+    let upload = TempUpload::receive(listing.as_bytes(), &state.data_directory, usize::MAX).await?;
+    store_dump(
+        Dump { upload, details },
+        uploader,
+        state,
+        storage,
+        pool,
+        token_generator,
+    )
+    .await
+}
+
+async fn store_multipart_upload(
+    req: &Request,
+    multipart_upload: MultipartUpload,
+    uploader: &str,
+    state: &ServeArgs,
+    storage: &dyn Storage,
+    pool: &DbPool,
+    token_generator: &TokenGenerator,
+) -> Result<Response> {
+    if multipart_upload.dumps.len() == 1 && !multipart_upload.collection {
+        let dump = multipart_upload.dumps.into_iter().next().unwrap();
+        return Ok(uploaded_file_response(
+            req,
+            store_dump(dump, uploader, state, storage, pool, token_generator).await?,
+        ));
+    }
+    let collection_details = DumpDetails {
+        file_name: "collection.txt".to_string(),
+        secret: multipart_upload.dumps[0].details.secret.clone(),
+        expires: multipart_upload
+            .dumps
+            .iter()
+            .map(|dump| dump.details.expires)
+            .max()
+            .unwrap(),
+        max_downloads: multipart_upload.dumps[0].details.max_downloads,
+        password: multipart_upload.dumps[0].details.password.clone(),
+        // A custom token can only be used once, so it is given to the collection
+        token: multipart_upload.dumps[0].details.token.clone(),
+        content_disposition: multipart_upload.dumps[0]
+            .details
+            .content_disposition
+            .clone(),
+    };
+    if collection_details.token.is_some() && !multipart_upload.collection {
+        return Err(BadRequest(DumpError::new(
+            "invalid_token",
+            "A custom token requires a single file or a collection".to_string(),
+        )));
+    }
+    // Files are stored one by one, the manifest lists the stored files next to the failed ones
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for mut dump in multipart_upload.dumps {
+        dump.details.token = None;
+        let file_name = dump.details.file_name.clone();
+        match store_dump(dump, uploader, state, storage, pool, token_generator).await {
+            Ok(file) => files.push(file),
+            Err(error) => errors.push((file_name, error)),
+        }
+    }
+    if files.is_empty() {
+        return Err(errors.into_iter().next().unwrap().1);
+    }
+    let failed = errors
+        .into_iter()
+        .map(|(file_name, error)| FailedFile {
+            file_name,
+            code: error_code(&error),
+            message: error.to_string().trim_end().to_string(),
+        })
+        .collect();
+    let collection = if multipart_upload.collection {
+        Some(
+            store_collection(
+                &files,
+                collection_details,
+                uploader,
+                state,
+                storage,
+                pool,
+                token_generator,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    Ok(Json(UploadManifest {
+        files,
+        failed,
+        collection,
+    })
+    .into_response())
+}
+
+// The settings which apply to an upload, with the overrides of its API key
+fn upload_settings(req: &Request, state: &Arc<ServeArgs>) -> Result<Arc<ServeArgs>> {
+    if state.require_api_key && request_api_key(req).is_none() {
+        return Err(Unauthorized(DumpError::new(
+            "api_key_required",
+            "Uploads require an API key".to_string(),
+        )));
+    }
+    Ok(request_settings(req, state))
 }
 
 #[handler]
-async fn get_file_handler(
-    Path(token): Path<String>,
+async fn dump_file_handler(
+    req: &Request,
+    body: Body,
     state: Data<&Arc<ServeArgs>>,
+    storage: Data<&Arc<dyn Storage>>,
+    pool: Data<&DbPool>,
+    token_generator: Data<&Arc<TokenGenerator>>,
 ) -> Result<Response> {
-    let connection = state
-        .create_connection()
-        .map_err(|e| InternalServerError(e))?;
-    let url = Url::search_url_by_token(&connection, &token).map_err(|x| InternalServerError(x))?;
+    let state = upload_settings(req, &state)?;
+    let is_multipart = req.content_type().map_or(false, |content_type| {
+        content_type.starts_with("multipart/form-data")
+    });
+    if is_multipart {
+        let multipart = Multipart::from_request(req, &mut RequestBody::new(body)).await?;
+        let multipart_upload = dump_parse_multipart(multipart, state.clone()).await?;
+        store_multipart_upload(
+            req,
+            multipart_upload,
+            &uploader_identity(req),
+            &state,
+            &storage,
+            &pool,
+            &token_generator,
+        )
+        .await
+    } else {
+        let file_name = header_text(req, "X-Filename")?.unwrap_or_else(|| "file".to_string());
+        let dump = dump_parse_raw(req, body, file_name, state.clone()).await?;
+        Ok(uploaded_file_response(
+            req,
+            store_dump(
+                dump,
+                &uploader_identity(req),
+                &state,
+                &storage,
+                &pool,
+                &token_generator,
+            )
+            .await?,
+        ))
+    }
+}
+
+#[handler]
+async fn put_file_handler(
+    Path(file_name): Path<String>,
+    req: &Request,
+    body: Body,
+    state: Data<&Arc<ServeArgs>>,
+    storage: Data<&Arc<dyn Storage>>,
+    pool: Data<&DbPool>,
+    token_generator: Data<&Arc<TokenGenerator>>,
+) -> Result<Response> {
+    let state = upload_settings(req, &state)?;
+    let dump = dump_parse_raw(req, body, file_name, state.clone()).await?;
+    Ok(uploaded_file_response(
+        req,
+        store_dump(
+            dump,
+            &uploader_identity(req),
+            &state,
+            &storage,
+            &pool,
+            &token_generator,
+        )
+        .await?,
+    ))
+}
+
+// The password may be passed via basic auth with an arbitrary user name, or the X-Password header
+fn request_password(req: &Request) -> Option<String> {
+    if let Some(authorization) = req.headers().typed_get::<Authorization<Basic>>() {
+        return Some(authorization.password().to_string());
+    }
+    req.header("X-Password").map(ToString::to_string)
+}
+
+// Unwraps the content key of encrypted urls. Links without the right link key are not found,
+// as they can not be told apart from guessed ones
+fn url_content_key(url: &Url, link_key: Option<&str>, state: &ServeArgs) -> Result<Option<Key>> {
+    let (encryption, wrapped_key) = match (&url.encryption, &url.wrapped_key) {
+        (Some(encryption), Some(wrapped_key)) => (encryption, wrapped_key),
+        _ => return Ok(None),
+    };
+    let encryption_error =
+        |message: &str| InternalServerError(DumpError::new("encryption", message.to_string()));
+    let encryption = Encryption::from_name(encryption)
+        .ok_or_else(|| encryption_error("Unknown encryption mode"))?;
+    if encryption == Encryption::Token && link_key.is_none() {
+        return Err(NotFoundError {}.into());
+    }
+    let wrapping_key = wrapping_key(
+        &encryption,
+        link_key.unwrap_or_default(),
+        state.master_key.as_deref(),
+    )
+    .map_err(encryption_error)?;
+    match unwrap_key(wrapped_key, &wrapping_key) {
+        Some(content_key) => Ok(Some(content_key)),
+        None if encryption == Encryption::Token => Err(NotFoundError {}.into()),
+        None => Err(encryption_error("Could not decrypt the file key")),
+    }
+}
+
+// Builds the response of a link without counting it as a download
+async fn url_file_response(
+    link: &str,
+    req: &Request,
+    state: &ServeArgs,
+    storage: &Arc<dyn Storage>,
+    pool: &DbPool,
+) -> Result<(Url, Response)> {
+    let (token, link_key) = split_link(link);
+    let search_token = token.to_string();
+    let url = with_connection(pool, move |conn| {
+        Url::search_url_by_token(conn, &search_token)
+    })
+    .await?;
     if url.is_none() {
         return Err(NotFoundError {}.into());
     }
@@ -196,47 +910,270 @@ async fn get_file_handler(
     if url.expired() {
         return Err(NotFoundError {}.into());
     }
-    let file = url.file(&connection).map_err(|x| InternalServerError(x))?;
-    let bytes = file
-        .read(state.data_directory.clone())
-        .map_err(|x| InternalServerError(x))?;
-    // TODO: detection
-    let body = Body::from_vec(bytes);
-    let mut builder = Response::builder()
-        .header(header::ACCEPT_RANGES, "bytes")
-        .header(header::CONTENT_LENGTH, file.size as u64)
+    let password_url = url.clone();
+    let password = request_password(req);
+    let valid =
+        tokio::task::spawn_blocking(move || password_url.check_password(password.as_deref()))
+            .await
+            .map_err(InternalServerError)?;
+    if !valid {
+        return Err(PasswordRequired::new().into());
+    }
+    let file_url = url.clone();
+    let file = with_connection(pool, move |conn| file_url.file(conn)).await?;
+    let content_key = url_content_key(&url, link_key, state)?;
+    // Caches must not hand out downloads which are limited or protected
+    let cache_control = if url.remaining_downloads.is_some() {
+        "no-store".to_string()
+    } else if url.password_hash.is_some() {
+        format!(
+            "private, max-age={}",
+            url.remaining_lifetime().num_seconds()
+        )
+    } else {
+        format!("public, max-age={}", url.remaining_lifetime().num_seconds())
+    };
+    let disposition = effective_disposition(&url, &file, state, download_requested(req));
+    let builder = Response::builder()
         .header(
             header::CONTENT_DISPOSITION,
-            format!("inline; filename=\"{}\"", url.file_name),
+            content_disposition_header(&disposition, &url.file_name),
         )
         .header("X-Expires", url.expires.to_string())
-        .content_type(file.mime);
-    Ok(builder.body(body))
+        // Browsers must not guess a type, which could turn a download into a page
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::CACHE_CONTROL, cache_control);
+    // Partial downloads of limited urls would each consume a download, so they send the
+    // whole file instead
+    let allow_ranges = url.remaining_downloads.is_none();
+    let response = file_response(
+        req,
+        builder,
+        storage,
+        &file,
+        content_key.as_ref(),
+        allow_ranges,
+    )
+    .await?;
+    Ok((url, response))
 }
 
 #[handler]
-async fn delete_url_handler(
-    Path((token, secret)): Path<(String, String)>,
+async fn get_file_handler(
+    Path(link): Path<String>,
+    req: &Request,
     state: Data<&Arc<ServeArgs>>,
+    storage: Data<&Arc<dyn Storage>>,
+    pool: Data<&DbPool>,
 ) -> Result<Response> {
-    let connection = state
-        .create_connection()
-        .map_err(|e| InternalServerError(e))?;
-    let url = Url::search_url_by_token(&connection, &token).map_err(|x| InternalServerError(x))?;
-    if url.is_none() {
-        return Err(NotFoundError {}.into());
+    let (url, response) = url_file_response(&link, req, &state, &storage, &pool).await?;
+    // A download is counted once its response is ready, before the body is sent. Aborted
+    // transfers count as well, while conditional requests answered with 304 do not
+    if response.status() != StatusCode::OK {
+        return Ok(response);
     }
-    let url = url.unwrap();
-    if url.secret != secret {
-        return Err(Forbidden(DumpError::new("Invalid secret".to_string())).into());
+    if url.remaining_downloads.is_none() {
+        with_connection(&pool, move |conn| url.record_download(conn)).await?;
+    } else {
+        let remaining_downloads = with_connection(&pool, move |conn| {
+            let remaining_downloads = url.consume_download(conn)?;
+            if remaining_downloads == Some(0) {
+                url.delete(conn)?;
+            }
+            Ok(remaining_downloads)
+        })
+        .await?;
+        if remaining_downloads.is_none() {
+            return Err(NotFoundError {}.into());
+        }
     }
-    url.delete(&connection)
-        .map_err(|x| InternalServerError(x))?;
+    Ok(response)
+}
+
+// Without an explicit HEAD handler poem would answer HEAD requests with the GET handler,
+// which counts them as downloads
+#[handler]
+async fn head_file_handler(
+    Path(link): Path<String>,
+    req: &Request,
+    state: Data<&Arc<ServeArgs>>,
+    storage: Data<&Arc<dyn Storage>>,
+    pool: Data<&DbPool>,
+) -> Result<Response> {
+    let (_url, mut response) = url_file_response(&link, req, &state, &storage, &pool).await?;
+    response.set_body(Body::empty());
+    Ok(response)
+}
+
+// Looks up a url which may only be managed with its secret. The link key is not needed
+async fn authorized_url(pool: &DbPool, token: &str, secret: String) -> Result<Url> {
+    let token = split_link(token).0.to_string();
+    let url = with_connection(pool, move |conn| Url::search_url_by_token(conn, &token)).await?;
+    let url = match url {
+        Some(url) if !url.expired() => url,
+        _ => return Err(NotFoundError {}.into()),
+    };
+    let secret_url = url.clone();
+    let valid = tokio::task::spawn_blocking(move || secret_url.check_secret(&secret))
+        .await
+        .map_err(InternalServerError)?;
+    if !valid {
+        return Err(Forbidden(DumpError::new(
+            "invalid_secret",
+            "Invalid secret".to_string(),
+        )));
+    }
+    Ok(url)
+}
+
+#[handler]
+async fn delete_url_handler(
+    Path((token, secret)): Path<(String, String)>,
+    pool: Data<&DbPool>,
+) -> Result<Response> {
+    let url = authorized_url(&pool, &token, secret).await?;
+    with_connection(&pool, move |conn| url.delete(conn)).await?;
     Ok(Response::builder()
         .status(StatusCode::OK)
         .body(Body::empty()))
 }
 
+#[derive(Serialize)]
+struct UrlInfo {
+    token: String,
+    file_name: String,
+    size: usize,
+    mime: String,
+    group: String,
+    expires: String,
+    downloads: u32,
+    remaining_downloads: Option<u32>,
+    password_protected: bool,
+    content_disposition: String,
+}
+
+async fn url_file(pool: &DbPool, url: &Url) -> Result<File> {
+    let file_url = url.clone();
+    with_connection(pool, move |conn| file_url.file(conn)).await
+}
+
+fn url_info(token: &str, url: Url, file: File, state: &ServeArgs) -> Json<UrlInfo> {
+    Json(UrlInfo {
+        content_disposition: effective_disposition(&url, &file, state, false).to_string(),
+        token: split_link(token).0.to_string(),
+        file_name: url.file_name,
+        size: file.size,
+        mime: file.mime,
+        group: file.group,
+        expires: url.expires.to_rfc3339(),
+        downloads: url.downloads,
+        remaining_downloads: url.remaining_downloads,
+        password_protected: url.password_hash.is_some(),
+    })
+}
+
+// Saves the changes to a url and responds with its new state
+async fn update_url(
+    token: &str,
+    url: Url,
+    file: File,
+    state: &ServeArgs,
+    pool: &DbPool,
+) -> Result<Json<UrlInfo>> {
+    let updated_url = url.clone();
+    with_connection(pool, move |conn| updated_url.update(conn)).await?;
+    Ok(url_info(token, url, file, state))
+}
+
+#[handler]
+async fn url_info_handler(
+    Path((token, secret)): Path<(String, String)>,
+    state: Data<&Arc<ServeArgs>>,
+    pool: Data<&DbPool>,
+) -> Result<Json<UrlInfo>> {
+    let url = authorized_url(&pool, &token, secret).await?;
+    let file = url_file(&pool, &url).await?;
+    Ok(url_info(&token, url, file, &state))
+}
+
+// The new duration starts now and is bounded like the duration of an upload of the same size,
+// including the overrides of the key which uploaded the file
+#[handler]
+async fn url_expires_handler(
+    Path((token, secret)): Path<(String, String)>,
+    body: String,
+    state: Data<&Arc<ServeArgs>>,
+    pool: Data<&DbPool>,
+) -> Result<Json<UrlInfo>> {
+    let mut url = authorized_url(&pool, &token, secret).await?;
+    let file = url_file(&pool, &url).await?;
+    let passed_expires = parse_expires(body.trim())?;
+    if passed_expires <= TimeDelta::zero() {
+        return Err(BadRequest(DumpError::new(
+            "invalid_expires",
+            "The expires duration must be larger than 0".to_string(),
+        )));
+    }
+    let uploader = url.uploader.clone();
+    let uploader_key = with_connection(&pool, move |conn| match uploader {
+        Some(uploader) => ApiKey::search_by_identity(conn, &uploader),
+        None => Ok(None),
+    })
+    .await?;
+    let settings = match uploader_key {
+        Some(key) => Arc::new(key.apply(&state)),
+        None => Arc::clone(&state),
+    };
+    let max_expires = calculate_expires(
+        file.size,
+        settings.min_expires,
+        settings.max_expires,
+        settings.max_size,
+    );
+    url.expires = Utc::now() + passed_expires.min(max_expires);
+    update_url(&token, url, file, &state, &pool).await
+}
+
+#[handler]
+async fn url_name_handler(
+    Path((token, secret)): Path<(String, String)>,
+    body: String,
+    state: Data<&Arc<ServeArgs>>,
+    pool: Data<&DbPool>,
+) -> Result<Json<UrlInfo>> {
+    let mut url = authorized_url(&pool, &token, secret).await?;
+    let file = url_file(&pool, &url).await?;
+    let file_name = body.trim();
+    if file_name.is_empty()
+        || file_name.len() > MAX_FILE_NAME_LENGTH
+        || file_name.chars().any(char::is_control)
+    {
+        return Err(BadRequest(DumpError::new(
+            "invalid_file_name",
+            format!(
+                "The file name must contain between 1 and {} bytes without control characters",
+                MAX_FILE_NAME_LENGTH
+            ),
+        )));
+    }
+    url.file_name = file_name.to_string();
+    update_url(&token, url, file, &state, &pool).await
+}
+
+#[handler]
+async fn url_disposition_handler(
+    Path((token, secret)): Path<(String, String)>,
+    body: String,
+    state: Data<&Arc<ServeArgs>>,
+    pool: Data<&DbPool>,
+) -> Result<Json<UrlInfo>> {
+    let mut url = authorized_url(&pool, &token, secret).await?;
+    let file = url_file(&pool, &url).await?;
+    let disposition = parse_disposition(&body)?;
+    url.content_disposition = Some(disposition.to_string());
+    update_url(&token, url, file, &state, &pool).await
+}
+
 #[handler]
 async fn index_handler(_state: Data<&Arc<ServeArgs>>) -> Html<&'static str> {
     Html(include_str!("../assets/index.html"))
@@ -263,42 +1200,122 @@ fn ensure_model_files(data_directory: &std::path::Path) {
 }
 
 #[handler]
-async fn get_used(state: Data<&Arc<ServeArgs>>) -> Result<String> {
-    let connection = state
-        .create_connection()
-        .map_err(|e| InternalServerError(e))?;
-    let size_sum = File::size_sum(&connection).map_err(|x| InternalServerError(x))?;
+async fn get_used(pool: Data<&DbPool>) -> Result<String> {
+    let size_sum = with_connection(&pool, File::stored_size_sum).await?;
     Ok(size_sum.to_string())
 }
 
+#[derive(Serialize)]
+struct QuotaInfo {
+    identity: String,
+    // Consumed within the last 24 hours
+    used: UploadUsage,
+    daily_upload_bytes: Option<usize>,
+    daily_upload_files: Option<usize>,
+}
+
 #[handler]
-async fn get_used_percentage(state: Data<&Arc<ServeArgs>>) -> Result<String> {
-    let connection = state
-        .create_connection()
-        .map_err(|e| InternalServerError(e))?;
-    let size_sum = File::size_sum(&connection).map_err(|x| InternalServerError(x))?;
-    let percentage = size_sum as f64 / state.disk_quota as f64 * 100.0;
-    Ok(format!("{:.2}", percentage))
+async fn get_quota(
+    req: &Request,
+    state: Data<&Arc<ServeArgs>>,
+    pool: Data<&DbPool>,
+) -> Result<Json<QuotaInfo>> {
+    let identity = uploader_identity(req);
+    let uploader = identity.clone();
+    let used = with_connection(&pool, move |conn| {
+        Upload::usage_since(conn, &uploader, quota_window_start())
+    })
+    .await?;
+    let daily_limits = DailyLimits::from_args(&request_settings(req, &state));
+    Ok(Json(QuotaInfo {
+        identity,
+        used,
+        daily_upload_bytes: daily_limits.bytes,
+        daily_upload_files: daily_limits.files,
+    }))
 }
 
 #[handler]
-async fn get_settings(state: Data<&Arc<ServeArgs>>) -> Result<Json<ServeArgs>> {
-    Ok(Json(state.as_ref().clone()))
+async fn get_used_percentage(state: Data<&Arc<ServeArgs>>, pool: Data<&DbPool>) -> Result<String> {
+    let size_sum = with_connection(&pool, File::stored_size_sum).await?;
+    let percentage = size_sum as f64 / state.disk_quota as f64 * 100.0;
+    Ok(format!("{:.2}", percentage))
 }
 
-macro_rules! create_rate_limit_layer {
-    ($rate_limit_count:expr, $rate_limit_duration:expr) => {
-        TowerLayerCompatExt::compat(RateLimitLayer::new($rate_limit_count, $rate_limit_duration))
-    };
+// Shows the limits of the API key of the request, if there is one
+#[handler]
+async fn get_settings(req: &Request, state: Data<&Arc<ServeArgs>>) -> Result<Json<ServeArgs>> {
+    Ok(Json(request_settings(req, &state).as_ref().clone()))
 }
 
 pub async fn serve(args: ServeArgs) {
     std::fs::create_dir_all(&args.data_directory.join("files"))
         .expect("Could not create files directory");
+    std::fs::create_dir_all(&args.data_directory.join("tmp"))
+        .expect("Could not create tmp directory");
     ensure_model_files(&args.data_directory);
-    let rate_limit_count = args.rate_limit_count;
-    let rate_limit_duration = args.rate_limit_duration;
-    let deny_ips_layer = build_deny_ips(&args);
+    migrate_database(&args.data_directory);
+    if args.encryption == Encryption::Master {
+        wrapping_key(&args.encryption, "", args.master_key.as_deref()).expect("Invalid master key");
+    }
+    // Building the generator prepares the blocklist, so it is shared by all uploads
+    let token_generator = match TokenGenerator::from_args(&args) {
+        Ok(token_generator) => Arc::new(token_generator),
+        Err(e) => {
+            eprintln!("Invalid token options: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Each budget has its own buckets, so uploads do not use up the downloads of a client
+    let rate_limit = |count: u64| {
+        RateLimit::new(
+            count,
+            args.rate_limit_duration,
+            args.rate_limit_max_clients as usize,
+        )
+    };
+    let general_limit = rate_limit(args.rate_limit_count);
+    let upload_limit = rate_limit(args.upload_rate_limit_count);
+    let download_limit = rate_limit(args.download_rate_limit_count);
+    let delete_limit = rate_limit(args.delete_rate_limit_count);
+    let api_key_failure_limit = rate_limit(args.api_key_failure_rate_limit_count);
+    let (access_filter, upload_filter) = match build_ip_filters(&args) {
+        Ok(filters) => filters,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let trusted_proxies = args.trusted_proxies.clone();
+    let proxy_header = args.proxy_header.clone();
+    let storage = build_storage(&args.storage, &args.data_directory);
+    record_storage(&args.storage, &args.data_directory).expect("Could not record the storage");
+    let pool = build_pool(
+        &args.data_directory,
+        args.db_pool_size,
+        args.db_busy_timeout,
+    );
+
+    // Runs in the background, as listing a large storage may take a while
+    let reconcile_storage = storage.clone();
+    let reconcile_pool = pool.clone();
+    let reconcile_directory = args.data_directory.clone();
+    let prune_missing = args.prune_missing;
+    let prune_orphans = removes_orphans(&args.storage, args.prune_orphans);
+    tokio::spawn(async move {
+        match reconcile(
+            &reconcile_directory,
+            reconcile_storage.as_ref(),
+            &reconcile_pool,
+            prune_missing,
+            prune_orphans,
+        )
+        .await
+        {
+            Ok(report) => report.log(),
+            Err(e) => log::error!("Could not reconcile storage: {}", e),
+        }
+    });
 
     let address = args.address.clone();
     // let db_path = args.data_directory.join("db.sqlite3");
@@ -306,58 +1323,166 @@ pub async fn serve(args: ServeArgs) {
     let app = Route::new()
         .at(
             "/",
-            get(index_handler)
-                .post(dump_file_handler)
-                .with(create_rate_limit_layer!(
-                    rate_limit_count,
-                    rate_limit_duration
-                )),
+            get(index_handler.with(general_limit.clone())).post(
+                dump_file_handler
+                    .with(upload_filter.clone())
+                    .with(upload_limit.clone()),
+            ),
         )
         .at(
             "/:token/:secret",
-            post(delete_url_handler).with(create_rate_limit_layer!(
-                rate_limit_count,
-                rate_limit_duration
-            )),
+            post(delete_url_handler).with(delete_limit),
         )
         .at(
-            "/:token",
-            get(get_file_handler).with(create_rate_limit_layer!(
-                rate_limit_count,
-                rate_limit_duration
-            )),
+            "/:token/:secret/info",
+            get(url_info_handler).with(general_limit.clone()),
         )
         .at(
-            "/settings",
-            get(get_settings).with(create_rate_limit_layer!(
-                rate_limit_count,
-                rate_limit_duration
-            )),
+            "/:token/:secret/expires",
+            post(url_expires_handler).with(general_limit.clone()),
         )
         .at(
-            "/used",
-            get(get_used).with(create_rate_limit_layer!(
-                rate_limit_count,
-                rate_limit_duration
-            )),
+            "/:token/:secret/name",
+            post(url_name_handler).with(general_limit.clone()),
         )
+        .at(
+            "/:token/:secret/disposition",
+            post(url_disposition_handler).with(general_limit.clone()),
+        )
+        .at(
+            "/:token",
+            get(get_file_handler.with(download_limit.clone()))
+                .head(head_file_handler.with(download_limit))
+                .put(put_file_handler.with(upload_filter).with(upload_limit)),
+        )
+        .at("/settings", get(get_settings).with(general_limit.clone()))
+        .at("/used", get(get_used).with(general_limit.clone()))
+        .at("/quota", get(get_quota).with(general_limit.clone()))
         .at(
             "/used_percent",
-            get(get_used_percentage).with(create_rate_limit_layer!(
-                rate_limit_count,
-                rate_limit_duration
-            )),
+            get(get_used_percentage).with(general_limit.clone()),
         )
-        .with(deny_ips_layer)
+        .with(ApiKeyAuth::new(api_key_failure_limit))
+        .with(access_filter)
+        .with(ResolveClientIp::new(trusted_proxies, proxy_header))
+        .with(JsonErrors)
         .with(CatchPanic::new().with_handler(|error| {
             log::error!("Internal server error: {:?}", error);
             Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Body::empty())
         }))
-        .with(AddData::new(data));
+        .with(AddData::new(data))
+        .with(AddData::new(storage))
+        .with(AddData::new(pool))
+        .with(AddData::new(token_generator));
     let _ = Server::new(TcpListener::bind(address))
         .name("dump")
         .run(app)
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use poem::http::{Method, Uri};
+    use poem::Endpoint;
+
+    use crate::opts::{Cli, Commands};
+    use crate::storage::MemoryStorage;
+    use crate::util::{hash_token, TestDirectory};
+
+    // Serves a single url of `hello.txt` which may be downloaded once
+    async fn limited_url(data_directory: &TestDirectory) -> (impl Endpoint, DbPool) {
+        let cli = Cli::try_parse_from([
+            "dump",
+            "serve",
+            "--url",
+            "http://localhost",
+            "--address",
+            "127.0.0.1:0",
+            "--data-directory",
+            data_directory.to_str().unwrap(),
+        ])
+        .unwrap();
+        let args = match cli.command {
+            Commands::Serve(args) => args,
+            _ => unreachable!(),
+        };
+        migrate_database(data_directory);
+        let pool = build_pool(data_directory, 1, std::time::Duration::from_secs(5));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let hash = "ab".repeat(32);
+        let upload = TempUpload::receive(&b"hello"[..], data_directory, usize::MAX)
+            .await
+            .unwrap();
+        storage.put(&hash, upload).await.unwrap();
+        let file = File::new(
+            hash.clone(),
+            5,
+            "text/plain".to_string(),
+            "text".to_string(),
+            false,
+            5,
+            None,
+        );
+        let url = Url::new(
+            hash_token("limited"),
+            hash,
+            hash_password("secret"),
+            Utc::now() + TimeDelta::hours(1),
+            "hello.txt".to_string(),
+            Some(1),
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            Some(Utc::now()),
+        );
+        with_connection(&pool, move |conn| {
+            file.create(conn)?;
+            url.create(conn)
+        })
+        .await
+        .unwrap();
+        let app = Route::new()
+            .at("/:token", get(get_file_handler).head(head_file_handler))
+            .with(AddData::new(Arc::new(args)))
+            .with(AddData::new(storage))
+            .with(AddData::new(pool.clone()));
+        (app, pool)
+    }
+
+    async fn request(app: &impl Endpoint, method: Method) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(Uri::from_static("/limited"))
+            .finish();
+        app.get_response(req).await.status()
+    }
+
+    async fn remaining_downloads(pool: &DbPool) -> Option<Option<u32>> {
+        with_connection(pool, |conn| Url::search_url_by_token(conn, "limited"))
+            .await
+            .unwrap()
+            .map(|url| url.remaining_downloads)
+    }
+
+    #[tokio::test]
+    async fn head_requests_do_not_consume_downloads() {
+        let data_directory = TestDirectory::new("head");
+        let (app, pool) = limited_url(&data_directory).await;
+
+        assert_eq!(request(&app, Method::HEAD).await, StatusCode::OK);
+        assert_eq!(request(&app, Method::HEAD).await, StatusCode::OK);
+        assert_eq!(remaining_downloads(&pool).await, Some(Some(1)));
+
+        assert_eq!(request(&app, Method::GET).await, StatusCode::OK);
+        assert_eq!(remaining_downloads(&pool).await, None);
+        assert_eq!(request(&app, Method::HEAD).await, StatusCode::NOT_FOUND);
+    }
+}
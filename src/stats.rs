@@ -1,4 +1,5 @@
 use crate::{
+    db::migrate_database,
     models::{File, Url},
     util::create_connection,
 };
@@ -8,6 +9,7 @@ use std::path::PathBuf;
 pub async fn stats(data_directory: PathBuf) {
     let files_directory = data_directory.join("files");
     std::fs::create_dir_all(&files_directory).expect("Could not create files directory");
+    migrate_database(&data_directory);
     let connection = create_connection(&data_directory).expect("Could not create connection");
     let file_count = File::count(&connection).expect("Could not count files");
     let url_count = Url::count(&connection).expect("Could not count urls");
@@ -15,11 +17,16 @@ pub async fn stats(data_directory: PathBuf) {
         Url::count_expired(&connection).expect("Could not search unlinked urls");
     let total_size = File::size_sum(&connection).expect("Could not get total size");
     let formatted_total_size = format_size(total_size as u64, DECIMAL);
+    let stored_size = File::stored_size_sum(&connection).expect("Could not get stored size");
+    let formatted_stored_size = format_size(stored_size as u64, DECIMAL);
 
     let mime_count = File::mime_count(&connection).expect("Could not count mimes");
     let group_count = File::group_count(&connection).expect("Could not count groups");
 
-    println!("Files: {} ({})", file_count, formatted_total_size);
+    println!(
+        "Files: {} ({}, {} stored)",
+        file_count, formatted_total_size, formatted_stored_size
+    );
     println!("Urls: {} ({} expired)", url_count, unlinked_url_count);
 
     if unlinked_url_count > 0 {
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::{GetOptions, GetRange, ObjectStore};
+use poem::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+use crate::opts::{StorageArgs, StorageBackend};
+use crate::upload::TempUpload;
+
+pub type BlobReader = Box<dyn AsyncRead + Send + Unpin>;
+
+// Stores the content of files, identified by their hash
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, id: &str, upload: TempUpload) -> io::Result<()>;
+
+    async fn open(&self, id: &str, range: Range<u64>) -> io::Result<BlobReader>;
+
+    async fn modified(&self, id: &str) -> io::Result<SystemTime>;
+
+    async fn delete(&self, id: &str) -> io::Result<()>;
+
+    // Returns the ids of all stored blobs with their modification time
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>>;
+}
+
+pub fn build_storage(args: &StorageArgs, data_directory: &Path) -> Arc<dyn Storage> {
+    match args.storage {
+        StorageBackend::FileSystem => {
+            let directory = data_directory.join("files");
+            if has_flat_files(&directory) {
+                panic!("The files directory uses the flat layout of older versions, run `dump migrate-layout` first");
+            }
+            Arc::new(FileSystemStorage { directory })
+        }
+        StorageBackend::S3 => {
+            let mut builder = AmazonS3Builder::from_env().with_allow_http(args.s3_allow_http);
+            if let Some(bucket) = &args.s3_bucket {
+                builder = builder.with_bucket_name(bucket);
+            }
+            if let Some(endpoint) = &args.s3_endpoint {
+                builder = builder.with_endpoint(endpoint);
+            }
+            if let Some(region) = &args.s3_region {
+                builder = builder.with_region(region);
+            }
+            Arc::new(S3Storage {
+                store: builder.build().expect("Could not configure S3 storage"),
+            })
+        }
+        StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+    }
+}
+
+// The storage options of `dump serve` are recorded in the data directory, so `dump clean`
+// can refuse to run against a different storage, where every blob would seem to be missing
+const STORAGE_FILE: &str = "storage";
+
+fn storage_description(args: &StorageArgs) -> String {
+    match args.storage {
+        StorageBackend::S3 => format!(
+            "{} {} {}",
+            args.storage,
+            args.s3_bucket.as_deref().unwrap_or_default(),
+            args.s3_endpoint.as_deref().unwrap_or_default()
+        ),
+        _ => args.storage.to_string(),
+    }
+}
+
+// A bucket may be shared with other instances, whose blobs are unknown to the local database.
+// Orphaned blobs are therefore only removed from it when asked to
+pub fn removes_orphans(args: &StorageArgs, prune_orphans: bool) -> bool {
+    prune_orphans || args.storage != StorageBackend::S3
+}
+
+pub fn record_storage(args: &StorageArgs, data_directory: &Path) -> io::Result<()> {
+    std::fs::write(
+        data_directory.join(STORAGE_FILE),
+        storage_description(args) + "\n",
+    )
+}
+
+// Data directories which were never served with a recorded storage pass
+pub fn check_storage(args: &StorageArgs, data_directory: &Path) -> Result<(), String> {
+    let recorded = match std::fs::read_to_string(data_directory.join(STORAGE_FILE)) {
+        Ok(recorded) => recorded,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let description = storage_description(args);
+    if recorded.trim_end() != description {
+        return Err(format!(
+            "The data directory is served with the storage `{}`, not `{}`",
+            recorded.trim_end(),
+            description
+        ));
+    }
+    Ok(())
+}
+
+// Blobs are named after the hex encoded SHA-256 hash of their content or content key
+pub fn is_blob_id(id: &str) -> bool {
+    id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Files are spread over two levels of directories named after the start of their hash,
+// e.g. `files/ab/cd/abcdef...`, to keep directories small
+pub fn sharded_path(directory: &Path, id: &str) -> PathBuf {
+    match (id.get(0..2), id.get(2..4)) {
+        (Some(first), Some(second)) => directory.join(first).join(second).join(id),
+        _ => directory.join(id),
+    }
+}
+
+// Whether blobs of the flat layout are left in the top level of the files directory
+fn has_flat_files(directory: &Path) -> bool {
+    std::fs::read_dir(directory).map_or(false, |entries| {
+        entries.flatten().any(|entry| {
+            entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_file())
+                && is_blob_id(&entry.file_name().to_string_lossy())
+        })
+    })
+}
+
+// Data directories of older versions must be migrated with `dump migrate-layout` first
+pub struct FileSystemStorage {
+    directory: PathBuf,
+}
+
+#[async_trait]
+impl Storage for FileSystemStorage {
+    async fn put(&self, id: &str, upload: TempUpload) -> io::Result<()> {
+        let path = sharded_path(&self.directory, id);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        upload.persist(&path)
+    }
+
+    async fn open(&self, id: &str, range: Range<u64>) -> io::Result<BlobReader> {
+        let mut file = tokio::fs::File::open(sharded_path(&self.directory, id)).await?;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(Box::new(file.take(range.end - range.start)))
+    }
+
+    async fn modified(&self, id: &str) -> io::Result<SystemTime> {
+        tokio::fs::metadata(sharded_path(&self.directory, id))
+            .await?
+            .modified()
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<()> {
+        tokio::fs::remove_file(sharded_path(&self.directory, id)).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        let mut blobs = Vec::new();
+        let mut directories = vec![self.directory.clone()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                } else {
+                    let id = entry.file_name().to_string_lossy().to_string();
+                    blobs.push((id, metadata.modified()?));
+                }
+            }
+        }
+        Ok(blobs)
+    }
+}
+
+// Works with any S3 compatible object store, configured via the usual AWS_* environment variables
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+fn object_path(id: &str) -> object_store::path::Path {
+    object_store::path::Path::from(id)
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, id: &str, upload: TempUpload) -> io::Result<()> {
+        let location = object_path(id);
+        let (multipart_id, mut writer) = self
+            .store
+            .put_multipart(&location)
+            .await
+            .map_err(io::Error::other)?;
+        let mut file = tokio::fs::File::open(&upload.path).await?;
+        let result = match tokio::io::copy(&mut file, &mut writer).await {
+            Ok(_) => writer.shutdown().await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = self.store.abort_multipart(&location, &multipart_id).await;
+        }
+        result
+    }
+
+    async fn open(&self, id: &str, range: Range<u64>) -> io::Result<BlobReader> {
+        if range.is_empty() {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let options = GetOptions {
+            range: Some(GetRange::Bounded(range.start as usize..range.end as usize)),
+            ..Default::default()
+        };
+        let result = self
+            .store
+            .get_opts(&object_path(id), options)
+            .await
+            .map_err(io::Error::other)?;
+        let stream = result.into_stream().map_err(io::Error::other);
+        Ok(Box::new(StreamReader::new(stream)))
+    }
+
+    async fn modified(&self, id: &str) -> io::Result<SystemTime> {
+        let meta = self
+            .store
+            .head(&object_path(id))
+            .await
+            .map_err(io::Error::other)?;
+        Ok(meta.last_modified.into())
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<()> {
+        self.store
+            .delete(&object_path(id))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        self.store
+            .list(None)
+            .map_ok(|meta| (meta.location.to_string(), meta.last_modified.into()))
+            .try_collect()
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+// Keeps all files in memory, only useful for tests and throwaway instances
+#[derive(Default)]
+pub struct MemoryStorage {
+    blobs: RwLock<HashMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    fn get(&self, id: &str) -> io::Result<(Bytes, SystemTime)> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, id: &str, upload: TempUpload) -> io::Result<()> {
+        let bytes = tokio::fs::read(&upload.path).await?;
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(id.to_string(), (Bytes::from(bytes), SystemTime::now()));
+        Ok(())
+    }
+
+    async fn open(&self, id: &str, range: Range<u64>) -> io::Result<BlobReader> {
+        let (bytes, _modified) = self.get(id)?;
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(range.start);
+        Ok(Box::new(cursor.take(range.end - range.start)))
+    }
+
+    async fn modified(&self, id: &str) -> io::Result<SystemTime> {
+        self.get(id).map(|(_bytes, modified)| modified)
+    }
+
+    async fn delete(&self, id: &str) -> io::Result<()> {
+        self.blobs.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        Ok(self
+            .blobs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, (_bytes, modified))| (id.clone(), *modified))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::TestDirectory;
+
+    async fn read(storage: &dyn Storage, id: &str, range: Range<u64>) -> Vec<u8> {
+        let mut content = Vec::new();
+        storage
+            .open(id, range)
+            .await
+            .unwrap()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+        content
+    }
+
+    // Exercises every operation of a backend with a single blob
+    async fn round_trip(storage: &dyn Storage, data_directory: &Path) {
+        let id = "0123456789abcdef";
+        let upload = TempUpload::receive(&b"hello storage"[..], data_directory, usize::MAX)
+            .await
+            .unwrap();
+        storage.put(id, upload).await.unwrap();
+
+        assert_eq!(read(storage, id, 0..13).await, b"hello storage");
+        assert_eq!(read(storage, id, 6..13).await, b"storage");
+        assert_eq!(read(storage, id, 3..3).await, b"");
+        assert!(storage.modified(id).await.unwrap() <= SystemTime::now());
+        let ids: Vec<String> = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _modified)| id)
+            .collect();
+        assert_eq!(ids, vec![id.to_string()]);
+
+        storage.delete(id).await.unwrap();
+        assert!(storage.open(id, 0..1).await.is_err());
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        round_trip(&MemoryStorage::default(), &TestDirectory::new("memory")).await;
+    }
+
+    #[tokio::test]
+    async fn file_system_storage() {
+        let data_directory = TestDirectory::new("fs");
+        let storage = FileSystemStorage {
+            directory: data_directory.join("files"),
+        };
+        round_trip(&storage, &data_directory).await;
+        assert!(!sharded_path(&storage.directory, "0123456789abcdef").exists());
+    }
+
+    // Runs against a MinIO or other S3 compatible server when DUMP_TEST_S3_ENDPOINT is set,
+    // e.g. `minio server /tmp/minio` with a `dump-test` bucket and the AWS_* credentials
+    #[tokio::test]
+    async fn s3_storage() {
+        let endpoint = match std::env::var("DUMP_TEST_S3_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let args = StorageArgs {
+            storage: StorageBackend::S3,
+            s3_bucket: Some(
+                std::env::var("DUMP_TEST_S3_BUCKET").unwrap_or_else(|_| "dump-test".to_string()),
+            ),
+            s3_endpoint: Some(endpoint),
+            s3_region: None,
+            s3_allow_http: true,
+        };
+        let data_directory = TestDirectory::new("s3");
+        round_trip(
+            build_storage(&args, &data_directory).as_ref(),
+            &data_directory,
+        )
+        .await;
+    }
+
+    #[test]
+    fn records_storage() {
+        let data_directory = TestDirectory::new("record");
+        let mut args = StorageArgs {
+            storage: StorageBackend::S3,
+            s3_bucket: Some("dump".to_string()),
+            s3_endpoint: Some("http://localhost:9000".to_string()),
+            s3_region: None,
+            s3_allow_http: true,
+        };
+        assert!(check_storage(&args, &data_directory).is_ok());
+        record_storage(&args, &data_directory).unwrap();
+        assert!(check_storage(&args, &data_directory).is_ok());
+        args.storage = StorageBackend::FileSystem;
+        assert!(check_storage(&args, &data_directory).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use rand::random;
+use sqids::Sqids;
+
+use crate::opts::ServeArgs;
+
+// Paths which are served by other routes and can not be used as tokens
+const RESERVED_TOKENS: &[&str] = &["settings", "used", "used_percent", "quota"];
+
+const MAX_CUSTOM_TOKEN_LENGTH: usize = 64;
+
+// Short blocked words may be contained in nearly every token
+const MAX_GENERATE_ATTEMPTS: usize = 100;
+
+// Characters which need no escaping in a path. `.` separates the link key of encrypted urls
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// Generates the tokens of access urls from random numbers
+pub struct TokenGenerator {
+    sqids: Sqids,
+    length: usize,
+    blocklist: Vec<String>,
+}
+
+impl TokenGenerator {
+    pub fn from_args(args: &ServeArgs) -> Result<TokenGenerator, String> {
+        let blocklist = args
+            .token_blocklist
+            .iter()
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<String>>();
+        let mut builder = Sqids::builder().min_length(args.token_length).blocklist(
+            sqids::default_blocklist()
+                .into_iter()
+                .chain(blocklist.iter().cloned())
+                .collect(),
+        );
+        if let Some(alphabet) = &args.token_alphabet {
+            if let Some(c) = alphabet.chars().find(|c| !is_token_char(*c)) {
+                return Err(format!(
+                    "The token alphabet contains {:?}, only letters, digits, '-' and '_' are allowed",
+                    c
+                ));
+            }
+            builder = builder.alphabet(alphabet.chars().collect());
+        }
+        Ok(TokenGenerator {
+            sqids: builder.build().map_err(|e| e.to_string())?,
+            length: args.token_length as usize,
+            blocklist,
+        })
+    }
+
+    // The encoded number is at least as long as the token, cutting it keeps the token length fixed.
+    // The cut token may still contain short blocked words, those tokens are generated again.
+    // Returns None if every attempt contained a blocked word
+    pub fn generate(&self) -> Option<String> {
+        for _attempt in 0..MAX_GENERATE_ATTEMPTS {
+            let token = self
+                .sqids
+                .encode(&[random::<u64>()])
+                .expect("Random numbers can be encoded");
+            let token = token.chars().take(self.length).collect::<String>();
+            if !self.is_blocked(&token) {
+                return Some(token);
+            }
+        }
+        None
+    }
+
+    fn is_blocked(&self, token: &str) -> bool {
+        let token = token.to_lowercase();
+        self.blocklist.iter().any(|word| token.contains(word))
+    }
+
+    // Custom tokens must be usable in a path and must not contain blocked words
+    pub fn is_valid_custom_token(&self, token: &str) -> bool {
+        !token.is_empty()
+            && token.len() <= MAX_CUSTOM_TOKEN_LENGTH
+            && token.chars().all(is_token_char)
+            && !RESERVED_TOKENS.contains(&token)
+            && !self.is_blocked(token)
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use poem::error::{BadRequest, InternalServerError, PayloadTooLarge};
+use poem::Result;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::serve::DumpError;
+use crate::util::random_token;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Number of bytes kept from the start and the end of an upload for file type detection
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+// An upload which has been streamed into the tmp directory, but not yet moved into the file store
+pub struct TempUpload {
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: usize,
+    pub sample: Vec<u8>,
+}
+
+impl TempUpload {
+    pub async fn receive<R: AsyncRead + Unpin>(
+        mut reader: R,
+        data_directory: &Path,
+        max_size: usize,
+    ) -> Result<TempUpload> {
+        let mut upload = TempUpload {
+            path: data_directory.join("tmp").join(random_token()),
+            hash: String::new(),
+            size: 0,
+            sample: Vec::new(),
+        };
+        let mut file = tokio::fs::File::create(&upload.path)
+            .await
+            .map_err(InternalServerError)?;
+        let mut hasher = Sha256::new();
+        let mut head: Vec<u8> = Vec::with_capacity(SAMPLE_SIZE);
+        let mut tail: Vec<u8> = Vec::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let read = reader.read(&mut buffer).await.map_err(|_e| {
+                BadRequest(DumpError::new(
+                    "upload_interrupted",
+                    "Could not read file".to_string(),
+                ))
+            })?;
+            if read == 0 {
+                break;
+            }
+            upload.size += read;
+            if upload.size > max_size {
+                return Err(PayloadTooLarge(DumpError::too_large(max_size)));
+            }
+            let chunk = &buffer[..read];
+            hasher.update(chunk);
+            file.write_all(chunk).await.map_err(InternalServerError)?;
+
+            let head_missing = (SAMPLE_SIZE - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..head_missing]);
+            tail.extend_from_slice(&chunk[head_missing..]);
+            if tail.len() > 2 * SAMPLE_SIZE {
+                tail.drain(..tail.len() - SAMPLE_SIZE);
+            }
+        }
+        file.flush().await.map_err(InternalServerError)?;
+
+        if tail.len() > SAMPLE_SIZE {
+            tail.drain(..tail.len() - SAMPLE_SIZE);
+        }
+        head.extend_from_slice(&tail);
+        upload.hash = format!("{:x}", hasher.finalize());
+        upload.sample = head;
+        Ok(upload)
+    }
+
+    // Atomically moves the upload to its final location
+    pub fn persist(&self, target: &Path) -> Result<(), std::io::Error> {
+        std::fs::rename(&self.path, target)
+    }
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        // Fails if the upload has already been persisted
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
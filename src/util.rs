@@ -1,18 +1,16 @@
 use std::path::PathBuf;
 
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use chrono::TimeDelta;
-use rand::random;
+use rand::{random, rngs::OsRng};
 use rusqlite::Connection;
+use sha2::{Digest, Sha256};
 use sqids::Sqids;
 
-use crate::models::{File, Url};
-
 pub fn create_connection(data_directory: &PathBuf) -> Result<Connection, rusqlite::Error> {
     let db_path = data_directory.join("db.sqlite3");
-    let conn = Connection::open(&db_path)?;
-    File::register_table(&conn);
-    Url::register_table(&conn);
-    Ok(conn)
+    Connection::open(&db_path)
 }
 
 pub fn random_token() -> String {
@@ -23,10 +21,41 @@ pub fn random_token() -> String {
         .unwrap()
         .encode(&data)
         .unwrap();
-    // token[0..10].to_string()
     token
 }
 
+// Urls are stored under the hash of their token, so the database does not contain working links.
+// Tokens are random or chosen to be unique, which makes a fast unsalted hash sufficient
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Splits the link key of encrypted urls off the token, e.g. `abc.key`
+pub fn split_link(token: &str) -> (&str, Option<&str>) {
+    match token.split_once('.') {
+        Some((token, link_key)) => (token, Some(link_key)),
+        None => (token, None),
+    }
+}
+
+// Returns a salted argon2 hash in the PHC string format
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Could not hash password")
+        .to_string()
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(password_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
 pub fn calculate_expires(
     size: usize,
     min_expires: usize,
@@ -39,5 +68,40 @@ pub fn calculate_expires(
     let millis = min_expires
         + ((-max_expires + min_expires) as f32 * (size as f32 / max_size as f32 - 1.0).powi(3))
             .floor() as i64;
-    TimeDelta::milliseconds(millis)
+    // Files above the maximum size, e.g. uploaded with a key, would otherwise get a negative duration
+    TimeDelta::milliseconds(millis.max(min_expires))
+}
+
+// A data directory for tests, removed again together with its contents when dropped
+#[cfg(test)]
+pub struct TestDirectory(PathBuf);
+
+#[cfg(test)]
+impl TestDirectory {
+    pub fn new(name: &str) -> TestDirectory {
+        let path = std::env::temp_dir().join(format!(
+            "dump-{}-{}-{:x}",
+            name,
+            std::process::id(),
+            random::<u64>()
+        ));
+        std::fs::create_dir_all(path.join("tmp")).unwrap();
+        TestDirectory(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDirectory {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
 }